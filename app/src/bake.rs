@@ -0,0 +1,237 @@
+use std::{
+    borrow::Cow,
+    error::Error,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use log::*;
+
+use crate::{
+    clock::{Clock, ManualClock},
+    model::Model,
+    timeline::{EventTrigger, Timeline, TimelineSnapshot, TrackSnapshot},
+};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum BakeFormat {
+    /// one row per track per frame
+    Csv,
+    /// one timeline snapshot per line, in the same shape as the `update` messages
+    Ndjson,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BakeArgs {
+    /// Name of the timeline to bake
+    timeline: String,
+    /// State file to load the timeline from
+    #[arg(long = "state", default_value = "state.json")]
+    state: String,
+    /// File to write the samples to. Defaults to standard output
+    #[arg(short, long = "output")]
+    output: Option<String>,
+    /// Output format
+    #[arg(long = "format", value_enum, default_value_t = BakeFormat::Csv)]
+    format: BakeFormat,
+    /// Frame rate to sample at. Defaults to the frame rate of the timeline
+    #[arg(long = "fps")]
+    fps: Option<u32>,
+    /// Amount of playback to bake, in seconds. Defaults to a single pass through the timeline.
+    /// Baking stops earlier if playback finishes before then.
+    #[arg(long = "duration")]
+    duration: Option<f64>,
+}
+
+/// Play back a timeline from a state file as fast as possible, writing out every frame it produces
+pub fn bake(args: &BakeArgs) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(&args.state)?;
+    let mut model: Model = serde_json::from_str(&contents)?;
+
+    // drive playback with a clock that only moves forward from one frame to the next, so that
+    // frames are produced exactly as they would be during live playback, without waiting for them
+    let clock = Arc::new(ManualClock::new());
+    model.set_clock(clock.clone());
+    model.compile_expressions();
+    let source = model
+        .get_timeline_mut(args.timeline.as_str())
+        .ok_or_else(|| format!("No timeline named {} in {}", args.timeline, args.state))?;
+    if let Some(fps) = args.fps {
+        source.fps = fps;
+    }
+    if source.fps == 0 {
+        return Err("Cannot bake a timeline at 0 fps".into());
+    }
+    let mut timeline = Timeline::from(source);
+    let end = Duration::from_secs_f64(args.duration.unwrap_or(timeline.duration).max(0.0));
+
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    });
+    if let BakeFormat::Csv = args.format {
+        writeln!(
+            writer,
+            "frame,time,position,track,value,mapped_value,values,mapped_values,units,state,r,g,b,a,events,ended_events,active_events"
+        )?;
+    }
+
+    // produce a frame at the start position before starting playback
+    timeline.stop();
+    let mut frame_count = 0;
+    while let Some(wait) = timeline.time_until_next_frame() {
+        clock.advance(wait);
+        if let Some(mut snapshot) = timeline.update() {
+            model.add_nested_snapshots(&mut snapshot);
+            write_snapshot(&mut writer, args.format, snapshot)?;
+            frame_count += 1;
+            break;
+        }
+    }
+
+    let started_at = clock.now();
+    timeline.play();
+    while timeline.is_playing() && clock.now().saturating_sub(started_at) < end {
+        match timeline.time_until_next_frame() {
+            Some(wait) => clock.advance(wait),
+            None => break,
+        }
+        if let Some(mut snapshot) = timeline.update() {
+            model.add_nested_snapshots(&mut snapshot);
+            write_snapshot(&mut writer, args.format, snapshot)?;
+            frame_count += 1;
+        }
+    }
+    writer.flush()?;
+
+    info!(
+        "Baked {} frames of timeline {} at {} fps",
+        frame_count, timeline.name, timeline.fps
+    );
+    Ok(())
+}
+
+fn write_snapshot(
+    writer: &mut impl Write,
+    format: BakeFormat,
+    mut snapshot: TimelineSnapshot,
+) -> Result<(), Box<dyn Error>> {
+    // events only get published for frames produced by playback, so leave out any others
+    if !snapshot.played {
+        clear_events(&mut snapshot.tracks);
+    }
+    match format {
+        BakeFormat::Csv => write_csv_rows(writer, &snapshot, &snapshot.tracks, "")?,
+        BakeFormat::Ndjson => {
+            serde_json::to_writer(&mut *writer, &snapshot)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+fn clear_events(tracks: &mut [TrackSnapshot]) {
+    tracks.iter_mut().for_each(|track| {
+        if let Some(events) = &mut track.events {
+            events.clear();
+        }
+        if let Some(events) = &mut track.ended_events {
+            events.clear();
+        }
+        if let Some(nested) = &mut track.timeline {
+            clear_events(&mut nested.tracks);
+        }
+    });
+}
+
+/// Write a row for each of the given tracks, followed by the rows of any nested timelines, with
+/// their track names prefixed by the name of the track they are nested in
+fn write_csv_rows(
+    writer: &mut impl Write,
+    snapshot: &TimelineSnapshot,
+    tracks: &[TrackSnapshot],
+    prefix: &str,
+) -> Result<(), Box<dyn Error>> {
+    for track in tracks {
+        let name = format!("{}{}", prefix, track.name);
+        let value = track.value.map(|v| v.to_string()).unwrap_or_default();
+        let mapped_value = track
+            .mapped_value
+            .map(|v| v.to_string())
+            .unwrap_or_default();
+        let values = match &track.values {
+            Some(values) => serde_json::to_string(values)?,
+            None => String::new(),
+        };
+        let mapped_values = match &track.mapped_values {
+            Some(values) => serde_json::to_string(values)?,
+            None => String::new(),
+        };
+        let units = track
+            .output
+            .as_ref()
+            .and_then(|output| output.units.as_deref())
+            .unwrap_or_default();
+        let color = track.color.as_ref().map_or_else(
+            || vec![String::new(); 4],
+            |c| {
+                vec![
+                    c.r.to_string(),
+                    c.g.to_string(),
+                    c.b.to_string(),
+                    c.a.to_string(),
+                ]
+            },
+        );
+        let events = event_data(&track.events)?;
+        let ended_events = event_data(&track.ended_events)?;
+        let active_events = event_data(&track.active_events)?;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            snapshot.frame,
+            snapshot.time,
+            snapshot.position,
+            csv_field(name.as_str()),
+            value,
+            mapped_value,
+            csv_field(values.as_str()),
+            csv_field(mapped_values.as_str()),
+            csv_field(units),
+            csv_field(track.state.as_deref().unwrap_or_default()),
+            color.join(","),
+            csv_field(events.as_str()),
+            csv_field(ended_events.as_str()),
+            csv_field(active_events.as_str())
+        )?;
+        if let Some(nested) = &track.timeline {
+            write_csv_rows(
+                writer,
+                snapshot,
+                &nested.tracks,
+                format!("{}/", name).as_str(),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Get the data of the given events as a JSON array, or nothing for tracks without events
+fn event_data(events: &Option<Vec<EventTrigger>>) -> Result<String, Box<dyn Error>> {
+    Ok(match events {
+        Some(events) => serde_json::to_string(&events.iter().map(|e| &e.data).collect::<Vec<_>>())?,
+        None => String::new(),
+    })
+}
+
+/// Quote a CSV field if it contains any characters that would otherwise break up the row
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
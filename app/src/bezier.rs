@@ -1,3 +1,5 @@
+use std::f64::consts::PI;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
@@ -6,11 +8,93 @@ pub struct Point2D {
     pub y: f64,
 }
 
+/// How the segment from one anchor point to the next is interpolated
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    /// cubic bezier curve through the control points
+    #[default]
+    Bezier,
+    Linear,
+    /// hold the value of the anchor point until the next one
+    Constant,
+    /// quadratic ease in and out
+    Quad,
+    /// cubic ease in and out
+    Cubic,
+    /// exponential ease in and out
+    Expo,
+    /// overshoot the next value and spring back to it
+    Elastic,
+    /// bounce off the next value before settling on it
+    Bounce,
+}
+
+impl Interpolation {
+    /// Map linear progress through a segment, from 0 to 1, onto eased progress. Not applicable
+    /// to bezier segments, which depend on their control points.
+    pub fn ease(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Interpolation::Bezier | Interpolation::Linear => t,
+            Interpolation::Constant => 0.0,
+            Interpolation::Quad => {
+                if t < 0.5 {
+                    2.0 * t * t
+                } else {
+                    1.0 - (-2.0 * t + 2.0).powi(2) / 2.0
+                }
+            }
+            Interpolation::Cubic => {
+                if t < 0.5 {
+                    4.0 * t * t * t
+                } else {
+                    1.0 - (-2.0 * t + 2.0).powi(3) / 2.0
+                }
+            }
+            Interpolation::Expo => {
+                if t == 0.0 || t == 1.0 {
+                    t
+                } else if t < 0.5 {
+                    2.0_f64.powf(20.0 * t - 10.0) / 2.0
+                } else {
+                    (2.0 - 2.0_f64.powf(-20.0 * t + 10.0)) / 2.0
+                }
+            }
+            Interpolation::Elastic => {
+                if t == 0.0 || t == 1.0 {
+                    t
+                } else {
+                    2.0_f64.powf(-10.0 * t) * ((t * 10.0 - 0.75) * (2.0 * PI / 3.0)).sin() + 1.0
+                }
+            }
+            Interpolation::Bounce => {
+                let n = 7.5625;
+                let d = 2.75;
+                if t < 1.0 / d {
+                    n * t * t
+                } else if t < 2.0 / d {
+                    let t = t - 1.5 / d;
+                    n * t * t + 0.75
+                } else if t < 2.5 / d {
+                    let t = t - 2.25 / d;
+                    n * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / d;
+                    n * t * t + 0.984375
+                }
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub struct AnchorPoint {
     pub anchor: Point2D,
     pub control_1: Point2D,
     pub control_2: Point2D,
+    /// interpolation of the segment from this anchor point to the next
+    #[serde(default)]
+    pub interpolation: Interpolation,
 }
 
 pub type BezierCurve = Vec<AnchorPoint>;
@@ -28,6 +112,7 @@ impl Curve for BezierCurve {
             anchor,
             control_1,
             control_2,
+            interpolation: Interpolation::default(),
         });
         self.sort_by(|a, b| a.anchor.x.total_cmp(&b.anchor.x));
     }
@@ -37,18 +122,40 @@ impl Curve for BezierCurve {
             None
         } else if let Some(prev) = self.get_point_before(position) {
             if let Some(next) = self.get_point_after(position) {
-                let t = find_t_for_x(
-                    prev.anchor.x,
-                    prev.control_2.x,
-                    next.control_1.x,
-                    next.anchor.x,
-                    position,
-                    0.0001,
-                );
-                Some(
-                    get_point_on_curve(prev.anchor, prev.control_2, next.control_1, next.anchor, t)
-                        .y,
-                )
+                match prev.interpolation {
+                    Interpolation::Bezier => {
+                        let t = find_t_for_x(
+                            prev.anchor.x,
+                            prev.control_2.x,
+                            next.control_1.x,
+                            next.anchor.x,
+                            position,
+                            0.0001,
+                        );
+                        Some(
+                            get_point_on_curve(
+                                prev.anchor,
+                                prev.control_2,
+                                next.control_1,
+                                next.anchor,
+                                t,
+                            )
+                            .y,
+                        )
+                    }
+                    interpolation => {
+                        let width = next.anchor.x - prev.anchor.x;
+                        let progress = if width > 0.0 {
+                            (position - prev.anchor.x) / width
+                        } else {
+                            0.0
+                        };
+                        Some(
+                            prev.anchor.y
+                                + (next.anchor.y - prev.anchor.y) * interpolation.ease(progress),
+                        )
+                    }
+                }
             } else {
                 None
             }
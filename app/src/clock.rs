@@ -0,0 +1,61 @@
+use std::{
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Source of time for timeline playback. Time is expressed as the duration since an arbitrary,
+/// fixed point in time, and should never go backwards.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Duration;
+}
+
+/// Clock based on the monotonic system clock, which is unaffected by changes to the wall clock
+/// time, e.g. when NTP steps the clock
+#[derive(Debug)]
+pub struct MonotonicClock {
+    origin: Instant,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Duration {
+        self.origin.elapsed()
+    }
+}
+
+/// Clock used for live playback, unless another one is set
+pub fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(MonotonicClock::new())
+}
+
+/// Clock that only moves forward when told to, which allows for stepping through playback frame
+/// by frame, e.g. when rendering offline
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    time: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward by the given amount of time
+    pub fn advance(&self, duration: Duration) {
+        *self.time.lock().expect("mutex is poisoned") += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.time.lock().expect("mutex is poisoned")
+    }
+}
@@ -0,0 +1,549 @@
+use std::fmt;
+
+/// Reason why an expression could not be used
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExpressionError {
+    /// expression could not be parsed, with a description of the problem and the character
+    /// position at which it occurred
+    Syntax(String, usize),
+    UnknownTrack(String),
+    UnknownComponent(String, String),
+    /// referenced track doesn't produce a single value, e.g. because it is a color or vector
+    /// track, of which only the components can be referred to
+    NoValue(String),
+    UnknownFunction(String),
+    /// function was called with the wrong number of arguments
+    ArgumentCount(String, usize),
+    /// expression depends on its own value, directly or through other expressions
+    Cycle,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpressionError::Syntax(message, position) => {
+                write!(f, "{} at character {}", message, position)
+            }
+            ExpressionError::UnknownTrack(track) => write!(f, "Unknown track {}", track),
+            ExpressionError::UnknownComponent(track, component) => {
+                write!(f, "Track {} has no component {}", track, component)
+            }
+            ExpressionError::NoValue(track) => {
+                write!(f, "Track {} has no single value to refer to", track)
+            }
+            ExpressionError::UnknownFunction(name) => write!(f, "Unknown function {}", name),
+            ExpressionError::ArgumentCount(name, count) => {
+                write!(f, "Function {} cannot take {} argument(s)", name, count)
+            }
+            ExpressionError::Cycle => write!(f, "Expression depends on its own value"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Function {
+    Min,
+    Max,
+    Clamp,
+    Abs,
+    Floor,
+    Ceil,
+    Round,
+    Sqrt,
+    Pow,
+    Sin,
+    Cos,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "min" => Function::Min,
+            "max" => Function::Max,
+            "clamp" => Function::Clamp,
+            "abs" => Function::Abs,
+            "floor" => Function::Floor,
+            "ceil" => Function::Ceil,
+            "round" => Function::Round,
+            "sqrt" => Function::Sqrt,
+            "pow" => Function::Pow,
+            "sin" => Function::Sin,
+            "cos" => Function::Cos,
+            _ => return None,
+        })
+    }
+
+    fn accepts(&self, count: usize) -> bool {
+        match self {
+            Function::Min | Function::Max => count > 0,
+            Function::Clamp => count == 3,
+            Function::Pow => count == 2,
+            _ => count == 1,
+        }
+    }
+
+    fn apply(&self, args: &[f64]) -> f64 {
+        match self {
+            Function::Min => args.iter().copied().fold(f64::INFINITY, f64::min),
+            Function::Max => args.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Function::Clamp => args[0].max(args[1]).min(args[2]),
+            Function::Abs => args[0].abs(),
+            Function::Floor => args[0].floor(),
+            Function::Ceil => args[0].ceil(),
+            Function::Round => args[0].round(),
+            Function::Sqrt => args[0].sqrt(),
+            Function::Pow => args[0].powf(args[1]),
+            Function::Sin => args[0].sin(),
+            Function::Cos => args[0].cos(),
+        }
+    }
+}
+
+/// Math expression over the values of other tracks, parsed from e.g. `min(1, a * b + 0.1)`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    /// time in seconds
+    Time,
+    /// normalized position
+    Position,
+    /// value of another track, or one of its components such as `color.a` or `vector.x`
+    Reference {
+        track: String,
+        component: Option<String>,
+    },
+    Negate(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(Function, Vec<Expression>),
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ExpressionError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            index: 0,
+            length: source.chars().count(),
+        };
+        let expression = parser.parse_sum()?;
+        match parser.peek() {
+            None => Ok(expression),
+            Some((position, _)) => Err(ExpressionError::Syntax(
+                String::from("Unexpected input"),
+                position,
+            )),
+        }
+    }
+
+    /// Get all track references in this expression, as track name and component
+    pub fn references(&self) -> Vec<(&str, Option<&str>)> {
+        match self {
+            Expression::Number(_) | Expression::Time | Expression::Position => Vec::new(),
+            Expression::Reference { track, component } => {
+                vec![(track.as_str(), component.as_deref())]
+            }
+            Expression::Negate(operand) => operand.references(),
+            Expression::Binary(_, left, right) => {
+                let mut references = left.references();
+                references.append(&mut right.references());
+                references
+            }
+            Expression::Call(_, args) => args.iter().flat_map(|arg| arg.references()).collect(),
+        }
+    }
+
+    /// Evaluate the expression at the given time and normalized position, getting the values of
+    /// track references from `resolve`. There is no result if any of the referenced tracks has
+    /// no value, or if the result is not a finite number.
+    pub fn evaluate(
+        &self,
+        resolve: &dyn Fn(&str, Option<&str>) -> Option<f64>,
+        time: f64,
+        position: f64,
+    ) -> Option<f64> {
+        let value = match self {
+            Expression::Number(value) => *value,
+            Expression::Time => time,
+            Expression::Position => position,
+            Expression::Reference { track, component } => {
+                resolve(track.as_str(), component.as_deref())?
+            }
+            Expression::Negate(operand) => -operand.evaluate(resolve, time, position)?,
+            Expression::Binary(operator, left, right) => {
+                let a = left.evaluate(resolve, time, position)?;
+                let b = right.evaluate(resolve, time, position)?;
+                match operator {
+                    Operator::Add => a + b,
+                    Operator::Subtract => a - b,
+                    Operator::Multiply => a * b,
+                    Operator::Divide => a / b,
+                    Operator::Modulo => a.rem_euclid(b),
+                    Operator::Power => a.powf(b),
+                }
+            }
+            Expression::Call(function, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| arg.evaluate(resolve, time, position))
+                    .collect::<Option<Vec<f64>>>()?;
+                function.apply(&args)
+            }
+        };
+        Some(value).filter(|value| value.is_finite())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    /// name, optionally followed by a component, e.g. `track` or `track.x`
+    Identifier(String),
+    Operator(Operator),
+    OpenParen,
+    CloseParen,
+    Comma,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExpressionError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = if c.is_ascii_digit() || c == '.' {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text
+                .parse::<f64>()
+                .map_err(|_| ExpressionError::Syntax(format!("Invalid number {}", text), start))?;
+            Token::Number(value)
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            Token::Identifier(chars[start..i].iter().collect())
+        } else {
+            i += 1;
+            match c {
+                '+' => Token::Operator(Operator::Add),
+                '-' => Token::Operator(Operator::Subtract),
+                '*' => Token::Operator(Operator::Multiply),
+                '/' => Token::Operator(Operator::Divide),
+                '%' => Token::Operator(Operator::Modulo),
+                '^' => Token::Operator(Operator::Power),
+                '(' => Token::OpenParen,
+                ')' => Token::CloseParen,
+                ',' => Token::Comma,
+                _ => {
+                    return Err(ExpressionError::Syntax(
+                        format!("Unexpected character {}", c),
+                        start,
+                    ))
+                }
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, with the usual precedence of arithmetic operators
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    /// length of the source, to report errors at the end of the expression
+    length: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(usize, &Token)> {
+        self.tokens
+            .get(self.index)
+            .map(|(position, token)| (*position, token))
+    }
+
+    fn next(&mut self) -> Result<(usize, Token), ExpressionError> {
+        let token = self.tokens.get(self.index).cloned().ok_or_else(|| {
+            ExpressionError::Syntax(String::from("Unexpected end of expression"), self.length)
+        })?;
+        self.index += 1;
+        Ok(token)
+    }
+
+    /// Consume the next token if it is one of the given operators
+    fn next_operator(&mut self, operators: &[Operator]) -> Option<Operator> {
+        match self.peek() {
+            Some((_, Token::Operator(operator))) if operators.contains(operator) => {
+                let operator = *operator;
+                self.index += 1;
+                Some(operator)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, expected: Token, description: &str) -> Result<(), ExpressionError> {
+        let (position, token) = self.next()?;
+        if token == expected {
+            Ok(())
+        } else {
+            Err(ExpressionError::Syntax(
+                format!("Expected {}", description),
+                position,
+            ))
+        }
+    }
+
+    fn parse_sum(&mut self) -> Result<Expression, ExpressionError> {
+        let mut expression = self.parse_product()?;
+        while let Some(operator) = self.next_operator(&[Operator::Add, Operator::Subtract]) {
+            let right = self.parse_product()?;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    fn parse_product(&mut self) -> Result<Expression, ExpressionError> {
+        let mut expression = self.parse_unary()?;
+        while let Some(operator) =
+            self.next_operator(&[Operator::Multiply, Operator::Divide, Operator::Modulo])
+        {
+            let right = self.parse_unary()?;
+            expression = Expression::Binary(operator, Box::new(expression), Box::new(right));
+        }
+        Ok(expression)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, ExpressionError> {
+        if self.next_operator(&[Operator::Subtract]).is_some() {
+            return Ok(Expression::Negate(Box::new(self.parse_unary()?)));
+        }
+        self.parse_power()
+    }
+
+    /// Powers bind tighter than negation and are right associative, so `-2^2` is -4 and
+    /// `2^3^2` is 2^9
+    fn parse_power(&mut self) -> Result<Expression, ExpressionError> {
+        let base = self.parse_primary()?;
+        if self.next_operator(&[Operator::Power]).is_some() {
+            let exponent = self.parse_unary()?;
+            return Ok(Expression::Binary(
+                Operator::Power,
+                Box::new(base),
+                Box::new(exponent),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, ExpressionError> {
+        let (position, token) = self.next()?;
+        match token {
+            Token::Number(value) => Ok(Expression::Number(value)),
+            Token::OpenParen => {
+                let expression = self.parse_sum()?;
+                self.expect(Token::CloseParen, "closing parenthesis")?;
+                Ok(expression)
+            }
+            Token::Identifier(name) => {
+                if let Some((_, Token::OpenParen)) = self.peek() {
+                    self.index += 1;
+                    return self.parse_call(name);
+                }
+                Ok(match name.as_str() {
+                    "time" => Expression::Time,
+                    "position" => Expression::Position,
+                    _ => match name.split_once('.') {
+                        Some((track, component)) => {
+                            if track.is_empty() || component.is_empty() || component.contains('.') {
+                                return Err(ExpressionError::Syntax(
+                                    format!("Invalid reference {}", name),
+                                    position,
+                                ));
+                            }
+                            Expression::Reference {
+                                track: String::from(track),
+                                component: Some(String::from(component)),
+                            }
+                        }
+                        None => Expression::Reference {
+                            track: name,
+                            component: None,
+                        },
+                    },
+                })
+            }
+            _ => Err(ExpressionError::Syntax(
+                String::from("Expected a number, reference or function call"),
+                position,
+            )),
+        }
+    }
+
+    /// Parse the arguments of a function call, after its opening parenthesis
+    fn parse_call(&mut self, name: String) -> Result<Expression, ExpressionError> {
+        let function = Function::from_name(name.as_str())
+            .ok_or(ExpressionError::UnknownFunction(name.clone()))?;
+        let mut args = Vec::new();
+        if let Some((_, Token::CloseParen)) = self.peek() {
+            self.index += 1;
+        } else {
+            loop {
+                args.push(self.parse_sum()?);
+                let (position, token) = self.next()?;
+                match token {
+                    Token::Comma => continue,
+                    Token::CloseParen => break,
+                    _ => {
+                        return Err(ExpressionError::Syntax(
+                            String::from("Expected comma or closing parenthesis"),
+                            position,
+                        ))
+                    }
+                }
+            }
+        }
+        if !function.accepts(args.len()) {
+            return Err(ExpressionError::ArgumentCount(name, args.len()));
+        }
+        Ok(Expression::Call(function, args))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate an expression in which `a` is 2, `b` is 3 and `color.r` is 0.5
+    fn evaluate(source: &str) -> Option<f64> {
+        let resolve = |track: &str, component: Option<&str>| match (track, component) {
+            ("a", None) => Some(2.0),
+            ("b", None) => Some(3.0),
+            ("color", Some("r")) => Some(0.5),
+            _ => None,
+        };
+        Expression::parse(source)
+            .unwrap()
+            .evaluate(&resolve, 10.0, 0.25)
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), Some(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Some(9.0));
+        assert_eq!(evaluate("10 - 4 - 3"), Some(3.0));
+        assert_eq!(evaluate("12 / 3 / 2"), Some(2.0));
+        assert_eq!(evaluate("1 + 7 % 4 * 2"), Some(7.0));
+        assert_eq!(evaluate("a * b + time * position"), Some(8.5));
+    }
+
+    #[test]
+    fn powers_are_right_associative() {
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Some(512.0));
+        assert_eq!(evaluate("2 * 3 ^ 2"), Some(18.0));
+        assert_eq!(evaluate("2 ^ -1"), Some(0.5));
+    }
+
+    #[test]
+    fn negation_binds_looser_than_powers() {
+        assert_eq!(evaluate("-2 ^ 2"), Some(-4.0));
+        assert_eq!(evaluate("(-2) ^ 2"), Some(4.0));
+        assert_eq!(evaluate("--a"), Some(2.0));
+        assert_eq!(evaluate("b * -a"), Some(-6.0));
+    }
+
+    #[test]
+    fn references_have_at_most_one_component() {
+        assert_eq!(evaluate("color.r * 4"), Some(2.0));
+        assert_eq!(
+            Expression::parse("min(a, color.r)").unwrap().references(),
+            vec![("a", None), ("color", Some("r"))]
+        );
+        assert!(matches!(
+            Expression::parse("a.b.c"),
+            Err(ExpressionError::Syntax(_, 0))
+        ));
+        assert!(matches!(
+            Expression::parse("1 + a."),
+            Err(ExpressionError::Syntax(_, 4))
+        ));
+    }
+
+    #[test]
+    fn functions_check_their_argument_count() {
+        assert_eq!(evaluate("min(b, a, 4)"), Some(2.0));
+        assert_eq!(evaluate("clamp(5, 0, a)"), Some(2.0));
+        assert_eq!(evaluate("pow(a, b)"), Some(8.0));
+        assert_eq!(
+            Expression::parse("clamp(1, 2)"),
+            Err(ExpressionError::ArgumentCount(String::from("clamp"), 2))
+        );
+        assert_eq!(
+            Expression::parse("max()"),
+            Err(ExpressionError::ArgumentCount(String::from("max"), 0))
+        );
+        assert_eq!(
+            Expression::parse("abs(1, 2)"),
+            Err(ExpressionError::ArgumentCount(String::from("abs"), 2))
+        );
+        assert_eq!(
+            Expression::parse("lerp(1, 2, 0.5)"),
+            Err(ExpressionError::UnknownFunction(String::from("lerp")))
+        );
+    }
+
+    #[test]
+    fn invalid_results_have_no_value() {
+        assert_eq!(evaluate("a / 0"), None);
+        assert_eq!(evaluate("0 / 0"), None);
+        assert_eq!(evaluate("sqrt(-a)"), None);
+        assert_eq!(evaluate("a + missing"), None);
+    }
+
+    #[test]
+    fn syntax_errors_report_their_position() {
+        assert_eq!(
+            Expression::parse("1 +"),
+            Err(ExpressionError::Syntax(
+                String::from("Unexpected end of expression"),
+                3
+            ))
+        );
+        assert_eq!(
+            Expression::parse("(1 + 2"),
+            Err(ExpressionError::Syntax(
+                String::from("Unexpected end of expression"),
+                6
+            ))
+        );
+        assert_eq!(
+            Expression::parse("1 2"),
+            Err(ExpressionError::Syntax(String::from("Unexpected input"), 2))
+        );
+        assert_eq!(
+            Expression::parse("a # b"),
+            Err(ExpressionError::Syntax(
+                String::from("Unexpected character #"),
+                2
+            ))
+        );
+    }
+}
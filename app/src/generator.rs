@@ -0,0 +1,240 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bezier::{BezierCurve, Curve};
+use crate::timeline::scale_curve;
+
+/// Number of steps used to integrate an animated frequency into a number of cycles
+const INTEGRATION_STEPS: u32 = 256;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    /// smooth random values, with a new random value at every cycle
+    Noise,
+}
+
+/// Generator parameter, which is either fixed or animated over the course of the timeline
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Parameter {
+    Constant(f64),
+    /// curve of the parameter value over the normalized timeline position
+    Curve(BezierCurve),
+}
+
+impl Parameter {
+    pub fn get_value_at_position(&self, position: f64) -> f64 {
+        match self {
+            Parameter::Constant(value) => *value,
+            Parameter::Curve(curve) => curve.get_value_at_position(position).unwrap_or_default(),
+        }
+    }
+}
+
+fn default_frequency() -> Parameter {
+    Parameter::Constant(1.0)
+}
+
+fn default_phase() -> Parameter {
+    Parameter::Constant(0.0)
+}
+
+fn default_amplitude() -> Parameter {
+    Parameter::Constant(0.5)
+}
+
+fn default_offset() -> Parameter {
+    Parameter::Constant(0.5)
+}
+
+/// Procedural source of values, which only depend on the position on the timeline so that they
+/// are the same for every playback
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Generator {
+    #[serde(default)]
+    pub waveform: Waveform,
+    /// number of cycles per second
+    #[serde(default = "default_frequency")]
+    pub frequency: Parameter,
+    /// offset within a cycle, from 0 to 1
+    #[serde(default = "default_phase")]
+    pub phase: Parameter,
+    /// half of the distance between the lowest and highest value
+    #[serde(default = "default_amplitude")]
+    pub amplitude: Parameter,
+    /// value around which the waveform oscillates
+    #[serde(default = "default_offset")]
+    pub offset: Parameter,
+    /// seed for the random values of noise
+    #[serde(default)]
+    pub seed: u64,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Self {
+            waveform: Waveform::default(),
+            frequency: default_frequency(),
+            phase: default_phase(),
+            amplitude: default_amplitude(),
+            offset: default_offset(),
+            seed: 0,
+        }
+    }
+}
+
+impl Generator {
+    /// Get the generated value at the given normalized position on a timeline of the given
+    /// duration in seconds
+    pub fn get_value_at_position(&self, position: f64, duration: f64) -> f64 {
+        let cycles =
+            self.get_cycles(position, duration) + self.phase.get_value_at_position(position);
+        let wave = match self.waveform {
+            Waveform::Sine => (2.0 * PI * cycles).sin(),
+            Waveform::Triangle => 4.0 * ((cycles + 0.75).rem_euclid(1.0) - 0.5).abs() - 1.0,
+            Waveform::Saw => 2.0 * (cycles + 0.5).rem_euclid(1.0) - 1.0,
+            Waveform::Square => {
+                if cycles.rem_euclid(1.0) < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Noise => smooth_noise(self.seed, cycles),
+        };
+        self.offset.get_value_at_position(position)
+            + self.amplitude.get_value_at_position(position) * wave
+    }
+
+    /// Get the number of cycles completed from the start of the timeline up to the given position.
+    /// An animated frequency gets integrated, so that changes in frequency don't make the
+    /// waveform jump.
+    fn get_cycles(&self, position: f64, duration: f64) -> f64 {
+        let time = position * duration;
+        match &self.frequency {
+            Parameter::Constant(frequency) => frequency * time,
+            Parameter::Curve(_) => {
+                let step = position / f64::from(INTEGRATION_STEPS);
+                let sum: f64 = (0..INTEGRATION_STEPS)
+                    .map(|i| {
+                        let a = self.frequency.get_value_at_position(f64::from(i) * step);
+                        let b = self
+                            .frequency
+                            .get_value_at_position(f64::from(i + 1) * step);
+                        (a + b) / 2.0
+                    })
+                    .sum();
+                sum * time / f64::from(INTEGRATION_STEPS)
+            }
+        }
+    }
+
+    /// Multiply the positions of all animated parameters by the given factor
+    pub fn scale_positions(&mut self, factor: f64) {
+        [
+            &mut self.frequency,
+            &mut self.phase,
+            &mut self.amplitude,
+            &mut self.offset,
+        ]
+        .into_iter()
+        .for_each(|parameter| {
+            if let Parameter::Curve(curve) = parameter {
+                scale_curve(curve, factor);
+            }
+        });
+    }
+}
+
+/// Random value from -1 to 1 for the given seed and lattice point
+fn hash(seed: u64, index: i64) -> f64 {
+    // splitmix64
+    let mut z = seed
+        ^ (index as u64)
+            .wrapping_add(1)
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// Value noise, smoothly interpolating between random values at whole numbers
+fn smooth_noise(seed: u64, x: f64) -> f64 {
+    let index = x.floor();
+    let t = x - index;
+    let t = t * t * (3.0 - 2.0 * t);
+    let a = hash(seed, index as i64);
+    let b = hash(seed, index as i64 + 1);
+    a + (b - a) * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bezier::{AnchorPoint, Interpolation, Point2D};
+
+    fn point(x: f64, y: f64) -> AnchorPoint {
+        AnchorPoint {
+            anchor: Point2D { x, y },
+            control_1: Point2D { x, y },
+            control_2: Point2D { x, y },
+            interpolation: Interpolation::Linear,
+        }
+    }
+
+    fn noise(seed: u64) -> Generator {
+        Generator {
+            waveform: Waveform::Noise,
+            frequency: Parameter::Constant(7.0),
+            seed,
+            ..Generator::default()
+        }
+    }
+
+    #[test]
+    fn values_only_depend_on_position() {
+        let generator = noise(42);
+        let positions = [0.0, 0.13, 0.5, 0.77, 1.0];
+        let forwards: Vec<f64> = positions
+            .iter()
+            .map(|position| generator.get_value_at_position(*position, 3.0))
+            .collect();
+        let backwards: Vec<f64> = positions
+            .iter()
+            .rev()
+            .map(|position| noise(42).get_value_at_position(*position, 3.0))
+            .collect();
+        assert_eq!(forwards, backwards.into_iter().rev().collect::<Vec<f64>>());
+        assert_ne!(
+            generator.get_value_at_position(0.5, 3.0),
+            noise(43).get_value_at_position(0.5, 3.0)
+        );
+    }
+
+    #[test]
+    fn noise_stays_within_amplitude() {
+        let generator = noise(7);
+        (0..1000).for_each(|i| {
+            let value = generator.get_value_at_position(f64::from(i) / 1000.0, 10.0);
+            assert!((0.0..=1.0).contains(&value), "{} out of range", value);
+        });
+    }
+
+    #[test]
+    fn animated_frequency_gets_integrated() {
+        let generator = Generator {
+            frequency: Parameter::Curve(vec![point(0.0, 1.0), point(1.0, 3.0)]),
+            ..Generator::default()
+        };
+        // the frequency rises linearly from 1 to 3 cycles per second over 2 seconds
+        assert!((generator.get_cycles(0.5, 2.0) - 1.5).abs() < 1e-9);
+        assert!((generator.get_cycles(1.0, 2.0) - 4.0).abs() < 1e-9);
+    }
+}
@@ -1,4 +1,5 @@
-use clap::Parser;
+use bake::BakeArgs;
+use clap::{Parser, Subcommand};
 use lazy_static::lazy_static;
 use log::*;
 use serde::{Serialize, Serializer};
@@ -8,24 +9,32 @@ use std::{
     error::Error,
     panic::catch_unwind,
     sync::{
-        mpsc::{self, SyncSender, TryRecvError},
+        mpsc::{self, RecvTimeoutError, Sender},
         Arc, Mutex,
     },
     thread,
-    time::SystemTime,
+    time::{Duration, Instant},
 };
 use tether::StatusMessage;
 
 use crate::model::Model;
 use crate::signal_handler::handle_exit_signals;
+use crate::stats::FrameStats;
 use crate::tether::{ControlMessage, Tether};
 
+mod bake;
 mod bezier;
+mod clock;
 mod color_gradient;
+mod expression;
+mod generator;
 mod model;
+mod playlist;
 mod server;
 mod signal_handler;
+mod stats;
 mod tether;
+mod timecode;
 mod timeline;
 
 #[derive(Parser, Debug)]
@@ -52,8 +61,24 @@ struct Args {
     /// Verbose mode (-v: warn, -vv: info, -vvv: debug, , -vvvv or more: trace)
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbosity: u8,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Render a timeline to a file of samples, without running the agent
+    Bake(BakeArgs),
+}
+
+/// Longest time to wait for incoming messages while no timeline needs updating, which also
+/// determines how quickly exit signals are picked up
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+/// Time to wait after the last change before saving the model state to file
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+/// Interval at which to report on the achieved frame rate and jitter
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
 lazy_static! {
     static ref ARGS: Args = Args::parse();
 }
@@ -104,7 +129,7 @@ fn init_logging(level: u8) {
     );
 }
 
-fn publish_state(tx: &SyncSender<StatusMessage>, model: &Arc<MutexWrapper<Model>>) {
+fn publish_state(tx: &Sender<StatusMessage>, model: &Arc<MutexWrapper<Model>>) {
     match tx.send(tether::StatusMessage::State(model.clone())) {
         Ok(()) => (),
         Err(err) => {
@@ -124,15 +149,18 @@ fn run(model: &Arc<MutexWrapper<Model>>) {
     let (tx_sig, rx_sig) = mpsc::sync_channel(1);
     handle_exit_signals(tx_sig).expect("Cannot handle exit signals");
 
-    let (tx_control, rx_control) = mpsc::sync_channel(1);
-    let (tx_status, rx_status) = mpsc::sync_channel(1);
+    // both threads send several messages at a time without waiting for the other to receive
+    // them, as they would otherwise end up waiting on each other
+    let (tx_control, rx_control) = mpsc::channel();
+    let (tx_status, rx_status) = mpsc::channel();
     let tether = Tether::new(tx_control, rx_status);
 
     publish_state(&tx_status, model);
 
     thread::spawn(move || tether.start());
 
-    let mut save_requested_at: Option<SystemTime> = None;
+    let mut save_requested_at: Option<Instant> = None;
+    let mut frame_stats = FrameStats::new(STATS_INTERVAL);
 
     loop {
         // Listen for exit signals
@@ -141,54 +169,94 @@ fn run(model: &Arc<MutexWrapper<Model>>) {
             panic!("Received exit signal");
         }
 
+        // sleep until the next frame or save is due, or until a control message comes in
+        let mut timeout = model
+            .0
+            .lock()
+            .unwrap()
+            .time_until_next_frame()
+            .map_or(IDLE_TIMEOUT, |t| t.min(IDLE_TIMEOUT));
+        if let Some(requested_at) = save_requested_at {
+            timeout =
+                timeout.min((requested_at + SAVE_DELAY).saturating_duration_since(Instant::now()));
+        }
+        let mut control_messages = Vec::new();
+        match rx_control.recv_timeout(timeout) {
+            Ok(message) => control_messages.push(message),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                error!("Error communicating with Tether agent: channel disconnected");
+                thread::sleep(timeout);
+            }
+        }
+        // handle any other messages that came in at the same time as well
+        control_messages.extend(rx_control.try_iter());
+
         let mut m = model.0.lock().unwrap();
 
         // keep track of any model changes, to determine if updated state data needs to be published
         let mut anything_changed = false;
 
-        // check for incoming data from tether, such as play/stop/seek requests
-        match rx_control.try_recv() {
-            Ok(ControlMessage::Update(timelines, selected_timeline)) => {
-                m.update_timeline_data(timelines);
-                m.set_active_timeline(selected_timeline.as_str());
-                anything_changed = true;
+        // status messages to send out once the model has been released
+        let mut status_messages = Vec::new();
+
+        // apply incoming data from tether, such as play/stop/seek requests
+        for message in control_messages {
+            anything_changed |= handle_control_message(&mut m, message, &mut status_messages);
+        }
+
+        // update all timelines, and send out a status message for each one that has a new frame
+        let updated_at = Instant::now();
+        for snapshot in m.update() {
+            debug!("Timeline state: {:?}", snapshot);
+            if snapshot.is_playing {
+                frame_stats.record(snapshot.name.as_str(), updated_at);
+            } else {
+                frame_stats.forget(snapshot.name.as_str());
             }
-            Ok(ControlMessage::Select(timeline)) => {
-                m.set_active_timeline(timeline.as_str());
+            status_messages.push(StatusMessage::Update(snapshot));
+        }
+
+        // move playlists on once their current timeline has finished, and publish their progress
+        for snapshot in m.update_playlists() {
+            debug!("Playlist state: {:?}", snapshot);
+            status_messages.push(StatusMessage::Playlist(snapshot));
+        }
+
+        // periodically report on the frame rate and jitter achieved for playing timelines
+        if let Some(reports) = frame_stats.report() {
+            reports.iter().for_each(|report| {
+                info!(
+                    "Timeline {}: {:.1} fps, {:.2} ms jitter, {:.2} ms max frame interval",
+                    report.timeline, report.fps, report.jitter_ms, report.max_interval_ms
+                );
+            });
+            if !reports.is_empty() {
+                status_messages.push(StatusMessage::Stats(reports));
             }
-            Ok(ControlMessage::Play(name)) => {
-                // select the specified timeline and start playback on it
-                m.set_active_timeline(name.as_str());
-                if let Some(timeline) = m.get_active_timeline_mut() {
-                    if timeline.name.eq(name.as_str()) {
-                        timeline.play();
-                        anything_changed = true;
+        }
+
+        if anything_changed {
+            save_requested_at = Some(Instant::now());
+        } else if let Some(requested_at) = save_requested_at {
+            if requested_at.elapsed() >= SAVE_DELAY {
+                info!("Saving model state to file");
+                match m.save_to_path("state.json") {
+                    Ok(()) => {
+                        info!("Model state saved to file successfully");
+                        save_requested_at = None
                     }
+                    Err(err) => error!("Could not save state to state.json, error: {}", err),
                 }
             }
-            Ok(ControlMessage::Stop) => {
-                // stop playback on current timeline
-                if let Some(timeline) = m.get_active_timeline_mut() {
-                    timeline.stop();
-                    // anything_changed = true;
-                }
-            }
-            Ok(ControlMessage::Seek(name, position)) => {
-                if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
-                    timeline.seek(position);
-                    // anything_changed = true;
-                }
-            }
-            Err(TryRecvError::Empty) => (),
-            Err(TryRecvError::Disconnected) => {
-                error!("Error communicating with Tether agent: channel disconnected");
-            }
         }
 
-        // update playing timeline, if any, and send out status messages over tether
-        if let Some(snapshot) = m.update() {
-            debug!("Timeline state: {:?}", snapshot);
-            match tx_status.send(tether::StatusMessage::Update(snapshot)) {
+        // release the model before handing messages to the tether agent, which needs to lock
+        // the model itself in order to publish its state
+        drop(m);
+
+        for message in status_messages {
+            match tx_status.send(message) {
                 Ok(()) => (),
                 Err(err) => {
                     error!(
@@ -202,27 +270,168 @@ fn run(model: &Arc<MutexWrapper<Model>>) {
         // publish the current model state
         if anything_changed {
             publish_state(&tx_status, model);
-            save_requested_at = Some(SystemTime::now());
-        } else if save_requested_at.is_some() {
-            if let Ok(elapsed) = save_requested_at.unwrap().elapsed() {
-                if elapsed.as_millis() >= 1000 {
-                    info!("Saving model state to file");
-                    match m.save_to_path("state.json") {
-                        Ok(()) => {
-                            info!("Model state saved to file successfully");
-                            save_requested_at = None
-                        }
-                        Err(err) => error!("Could not save state to state.json, error: {}", err),
-                    }
+        }
+    }
+}
+
+/// Apply a control message received over tether to the model, returning whether or not this
+/// changed the model state in a way that should be published and saved
+fn handle_control_message(
+    m: &mut Model,
+    message: ControlMessage,
+    status_messages: &mut Vec<StatusMessage>,
+) -> bool {
+    let mut anything_changed = false;
+    match message {
+        ControlMessage::Update(timelines, selected_timeline) => {
+            m.update_timeline_data(timelines);
+            m.quantize_events();
+            m.set_active_timeline(selected_timeline.as_str());
+            anything_changed = true;
+        }
+        ControlMessage::Select(timeline) => {
+            m.set_active_timeline(timeline.as_str());
+        }
+        ControlMessage::Play(name) => {
+            // start playback on the specified timeline, leaving any other timelines playing
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                timeline.play();
+                anything_changed = true;
+            }
+        }
+        ControlMessage::Pause(name) => {
+            // pause playback on the specified timeline, or the one currently selected in the UI
+            let timeline = match name {
+                Some(name) => m.get_timeline_mut(name.as_str()),
+                None => m.get_active_timeline_mut(),
+            };
+            if let Some(timeline) = timeline {
+                timeline.pause();
+            }
+        }
+        ControlMessage::Stop(name) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                timeline.stop();
+            }
+        }
+        ControlMessage::Resume(name) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                timeline.resume();
+            }
+        }
+        ControlMessage::Seek(name, target) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                if let Err(err) = timeline.seek_to(&target) {
+                    error!("Could not seek to {} on timeline {}: {}", target, name, err);
                 }
+                // anything_changed = true;
+            }
+        }
+        ControlMessage::Step(name, frames) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                if let Err(err) = timeline.step_frames(frames) {
+                    error!(
+                        "Could not step {} frames on timeline {}: {}",
+                        frames, name, err
+                    );
+                }
+            }
+        }
+        ControlMessage::Rate(name, rate) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                if let Err(err) = timeline.set_playback_rate(rate) {
+                    error!(
+                        "Could not set playback rate {} on timeline {}: {}",
+                        rate, name, err
+                    );
+                }
+            }
+        }
+        ControlMessage::SetRegion(name, in_point, out_point) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                match timeline.set_loop_region(in_point, out_point) {
+                    Ok(()) => anything_changed = true,
+                    Err(err) => error!(
+                        "Could not set loop region {}-{} on timeline {}: {}",
+                        in_point, out_point, name, err
+                    ),
+                }
+            }
+        }
+        ControlMessage::ClearRegion(name) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                timeline.clear_loop_region();
+                anything_changed = true;
+            }
+        }
+        ControlMessage::ReleaseRegion(name) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                timeline.release_loop_region();
+            }
+        }
+        ControlMessage::SeekMarker(name, marker) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                if let Err(err) = timeline.seek_to_marker(marker.as_str()) {
+                    error!(
+                        "Could not seek to marker {} on timeline {}: {}",
+                        marker, name, err
+                    );
+                }
+            }
+        }
+        ControlMessage::PlayToMarker(name, marker) => {
+            if let Some(timeline) = m.get_timeline_mut(name.as_str()) {
+                if let Err(err) = timeline.play_to_marker(marker.as_str()) {
+                    error!(
+                        "Could not play to marker {} on timeline {}: {}",
+                        marker, name, err
+                    );
+                }
+            }
+        }
+        ControlMessage::ListMarkers(name) => {
+            if let Some(timeline) = m.get_timeline(name.as_str()) {
+                status_messages.push(StatusMessage::Markers(name, timeline.markers.clone()));
+            }
+        }
+        ControlMessage::UpdatePlaylists(playlists) => {
+            m.update_playlist_data(playlists);
+            anything_changed = true;
+        }
+        ControlMessage::PlayPlaylist(name) => {
+            if let Err(err) = m.play_playlist(name.as_str()) {
+                error!("Could not play playlist {}: {}", name, err);
+            }
+        }
+        ControlMessage::NextInPlaylist(name) => {
+            if let Err(err) = m.next_in_playlist(name.as_str()) {
+                error!("Could not skip to next entry in playlist {}: {}", name, err);
+            }
+        }
+        ControlMessage::PreviousInPlaylist(name) => {
+            if let Err(err) = m.previous_in_playlist(name.as_str()) {
+                error!(
+                    "Could not skip to previous entry in playlist {}: {}",
+                    name, err
+                );
+            }
+        }
+        ControlMessage::StopPlaylist(name) => {
+            if let Err(err) = m.stop_playlist(name.as_str()) {
+                error!("Could not stop playlist {}: {}", name, err);
             }
         }
     }
+    anything_changed
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     init_logging(ARGS.verbosity);
 
+    if let Some(Command::Bake(args)) = &ARGS.command {
+        return bake::bake(args);
+    }
+
     let model: Arc<MutexWrapper<Model>> = Arc::new(MutexWrapper(Mutex::new(Model::new())));
     match model.0.lock().unwrap().load_from_path("state.json") {
         Ok(()) => (),
@@ -2,13 +2,17 @@ use std::{
     fs::{File, OpenOptions},
     io::{Read, Write},
     path::Path,
+    sync::Arc,
+    time::Duration,
 };
 
-use log::{debug, info};
+use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    timeline::{InvalidDataError, Result, Timeline, TimelineSnapshot},
+    clock::{default_clock, Clock},
+    playlist::{Playlist, PlaylistSnapshot},
+    timeline::{InvalidDataError, KeyframeTiming, LoopMode, Result, Timeline, TimelineSnapshot},
     ARGS,
 };
 
@@ -17,6 +21,11 @@ use crate::{
 pub struct Model {
     pub timelines: Vec<Timeline>,
     pub selected_timeline: Option<String>,
+    #[serde(default)]
+    pub playlists: Vec<Playlist>,
+    /// clock used to advance playback of all timelines
+    #[serde(skip, default = "default_clock")]
+    clock: Arc<dyn Clock>,
 }
 
 impl Model {
@@ -24,9 +33,19 @@ impl Model {
         Self {
             timelines: Vec::new(),
             selected_timeline: None,
+            playlists: Vec::new(),
+            clock: default_clock(),
         }
     }
 
+    /// Drive all timelines from the given clock, including those that come in with later updates
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.timelines
+            .iter_mut()
+            .for_each(|t| t.set_clock(clock.clone()));
+        self.clock = clock;
+    }
+
     pub fn load_from_path(&mut self, path: &str) -> std::io::Result<()> {
         let p = Path::new(path);
         if !p.exists() {
@@ -42,6 +61,7 @@ impl Model {
         file.read_to_string(&mut contents)?;
         if let Ok(data) = serde_json::from_str::<Model>(&contents) {
             self.update_timeline_data(data.timelines);
+            self.update_playlist_data(data.playlists);
             if let Some(selected_timeline) = data.selected_timeline {
                 self.set_active_timeline(selected_timeline.as_str());
             }
@@ -67,13 +87,14 @@ impl Model {
         &mut self,
         name: &str,
         duration: f64,
-        loop_playback: bool,
+        loop_mode: LoopMode,
     ) -> Result<&mut Timeline> {
         if let Some(_t) = self.timelines.iter().find(|t| t.name.eq(name)) {
             Err(InvalidDataError)
         } else {
-            self.timelines
-                .push(Timeline::new(name, duration, ARGS.fps, loop_playback));
+            let mut timeline = Timeline::new(name, duration, ARGS.fps, loop_mode);
+            timeline.set_clock(self.clock.clone());
+            self.timelines.push(timeline);
             self.selected_timeline = Some(String::from(name));
             Ok(self.timelines.last_mut().unwrap())
         }
@@ -97,53 +118,224 @@ impl Model {
         }
     }
 
-    /// Update the selected timeline. This stops playback on the previously
-    /// selected one.
+    /// Update the selected timeline, i.e. the one that is shown for editing.
+    /// This does not affect playback of any timeline.
     pub fn set_active_timeline(&mut self, name: &str) {
-        // do nothing is the timeline is already active
-        if let Some(selected) = &self.selected_timeline {
-            if selected.eq(name) {
-                return;
-            }
-        }
         if self.get_timeline(name).is_some() {
-            // stop playback on the current timeline
-            if let Some(current) = self.get_active_timeline_mut() {
-                current.stop();
-            }
             self.selected_timeline = Some(String::from(name));
         }
     }
 
-    /// Update the currently active timeline
-    pub fn update(&mut self) -> Option<TimelineSnapshot> {
-        if let Some(timeline) = self.get_active_timeline_mut() {
-            timeline.update()
-        } else {
-            None
+    /// Update all timelines, returning a snapshot for each timeline that has a new frame available
+    pub fn update(&mut self) -> Vec<TimelineSnapshot> {
+        let mut snapshots: Vec<TimelineSnapshot> = self
+            .timelines
+            .iter_mut()
+            .filter_map(|timeline| timeline.update())
+            .collect();
+        snapshots
+            .iter_mut()
+            .for_each(|snapshot| self.add_nested_snapshots(snapshot));
+        snapshots
+    }
+
+    /// Add snapshots of the timelines nested in any tracks of the given timeline snapshot
+    pub fn add_nested_snapshots(&self, snapshot: &mut TimelineSnapshot) {
+        let mut ancestors = vec![snapshot.name.clone()];
+        self.add_nested_snapshots_within(snapshot, &mut ancestors);
+    }
+
+    fn add_nested_snapshots_within(
+        &self,
+        snapshot: &mut TimelineSnapshot,
+        ancestors: &mut Vec<String>,
+    ) {
+        if let Some(timeline) = self.get_timeline(snapshot.name.as_str()) {
+            for (index, track) in timeline.get_tracks().iter().enumerate() {
+                if let Some(nested) = &track.timeline {
+                    if ancestors.contains(&nested.name) {
+                        // a timeline that is nested within itself would never stop nesting
+                        debug!(
+                            "Skipping timeline {} nested within itself in track {}",
+                            nested.name, track.name
+                        );
+                    } else if let Some(child) = self.get_timeline(nested.name.as_str()) {
+                        let mut child_snapshot = child.get_nested_snapshot(nested, snapshot);
+                        ancestors.push(nested.name.clone());
+                        self.add_nested_snapshots_within(&mut child_snapshot, ancestors);
+                        ancestors.pop();
+                        snapshot.tracks[index].timeline = Some(Box::new(child_snapshot));
+                    }
+                }
+            }
         }
     }
 
+    /// Get the time until the next timeline is due to produce a snapshot, if any
+    pub fn time_until_next_frame(&self) -> Option<Duration> {
+        self.timelines
+            .iter()
+            .filter_map(|timeline| timeline.time_until_next_frame())
+            .min()
+    }
+
     /// store new timelines, ensuring that any current play state gets applied to the same incoming timeline
     pub fn update_timeline_data(&mut self, mut data: Vec<Timeline>) {
         self.timelines = data.iter_mut().fold(Vec::new(), |mut list, t| {
             // clone the incoming timeline in a way that retains the current position and play state
+            t.set_clock(self.clock.clone());
             if let Some(original) = self.get_timeline(t.name.as_str()) {
-                t.seek(original.get_position());
-                if original.is_playing() {
-                    t.play()
+                t.copy_playback_state(original);
+                if t.keyframe_timing == KeyframeTiming::Absolute {
+                    t.retime(original.duration);
                 }
             }
             let timeline = Timeline::from(t);
             list.push(timeline);
             list
         });
+        self.compile_expressions();
+    }
+
+    /// Parse the expressions of all expression tracks, logging any that are invalid
+    pub fn compile_expressions(&mut self) {
+        self.timelines.iter_mut().for_each(|timeline| {
+            timeline
+                .compile_expressions()
+                .into_iter()
+                .for_each(|(track, err)| {
+                    error!(
+                        "Invalid expression on track {} of timeline {}: {}",
+                        track, timeline.name, err
+                    )
+                });
+        });
+    }
+
+    /// Snap the event positions of all timelines to the beat grid of their tempo, if any
+    pub fn quantize_events(&mut self) {
+        self.timelines
+            .iter_mut()
+            .for_each(|timeline| timeline.quantize_events());
+    }
+
+    /// store new playlists, ensuring that the progress of any playlist that is currently playing
+    /// gets applied to the same incoming playlist
+    pub fn update_playlist_data(&mut self, mut data: Vec<Playlist>) {
+        data.iter_mut().for_each(|p| {
+            if let Some(original) = self.get_playlist(p.name.as_str()) {
+                p.copy_progress(original);
+            }
+        });
+        self.playlists = data;
+    }
+
+    pub fn get_playlist(&self, name: &str) -> Option<&Playlist> {
+        self.playlists.iter().find(|p| p.name.eq(name))
+    }
+
+    fn get_playlist_index(&self, name: &str) -> Result<usize> {
+        self.playlists
+            .iter()
+            .position(|p| p.name.eq(name))
+            .ok_or(InvalidDataError)
+    }
+
+    /// Start the named playlist from its first entry
+    pub fn play_playlist(&mut self, name: &str) -> Result<()> {
+        let index = self.get_playlist_index(name)?;
+        self.start_playlist_entry(index, 0)
+    }
+
+    /// Skip to the next entry of the named playlist, which stops the playlist after the last entry
+    pub fn next_in_playlist(&mut self, name: &str) -> Result<()> {
+        let index = self.get_playlist_index(name)?;
+        let current = self.playlists[index]
+            .current_index()
+            .ok_or(InvalidDataError)?;
+        self.start_playlist_entry(index, current + 1)
+    }
+
+    /// Skip back to the previous entry of the named playlist, or restart its first entry
+    pub fn previous_in_playlist(&mut self, name: &str) -> Result<()> {
+        let index = self.get_playlist_index(name)?;
+        let current = self.playlists[index]
+            .current_index()
+            .ok_or(InvalidDataError)?;
+        self.start_playlist_entry(index, current.saturating_sub(1))
+    }
+
+    /// Stop the named playlist, along with the timeline it is currently playing
+    pub fn stop_playlist(&mut self, name: &str) -> Result<()> {
+        let index = self.get_playlist_index(name)?;
+        self.stop_playlist_timeline(index);
+        self.playlists[index].stop();
+        Ok(())
+    }
+
+    fn stop_playlist_timeline(&mut self, playlist: usize) {
+        if let Some(entry) = self.playlists[playlist].current_entry() {
+            if let Some(timeline) = self
+                .timelines
+                .iter_mut()
+                .find(|t| t.name.eq(entry.timeline.as_str()))
+            {
+                timeline.stop();
+            }
+        }
+    }
+
+    /// Stop the timeline currently playing in a playlist, and play the one at the given entry
+    fn start_playlist_entry(&mut self, playlist: usize, entry: usize) -> Result<()> {
+        self.stop_playlist_timeline(playlist);
+        if let Some(name) = self.playlists[playlist].go_to(entry) {
+            if let Some(timeline) = self.timelines.iter_mut().find(|t| t.name.eq(&name)) {
+                timeline.stop();
+                timeline.play();
+            } else {
+                self.playlists[playlist].stop();
+                return Err(InvalidDataError);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move playlists on to their next entry once their current timeline has finished, returning
+    /// a snapshot for each playlist that progressed
+    pub fn update_playlists(&mut self) -> Vec<PlaylistSnapshot> {
+        let timelines = &mut self.timelines;
+        self.playlists
+            .iter_mut()
+            .filter_map(|playlist| {
+                let finished = playlist.current_entry().is_some_and(|entry| {
+                    timelines
+                        .iter()
+                        .any(|t| t.name.eq(entry.timeline.as_str()) && t.has_finished())
+                });
+                if finished {
+                    if let Some(name) = playlist.advance() {
+                        if let Some(timeline) = timelines.iter_mut().find(|t| t.name.eq(&name)) {
+                            timeline.stop();
+                            timeline.play();
+                        } else {
+                            error!(
+                                "Playlist {} refers to unknown timeline {}",
+                                playlist.name, name
+                            );
+                            playlist.stop();
+                        }
+                    }
+                }
+                playlist.update()
+            })
+            .collect()
     }
 
     pub fn clean_up(&mut self) {
         self.timelines.iter_mut().for_each(|t| {
             t.stop();
         });
+        self.playlists.iter_mut().for_each(|p| p.stop());
         self.selected_timeline = None;
     }
 }
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+fn default_repeat() -> u32 {
+    1
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlaylistEntry {
+    /// name of the timeline to play
+    pub timeline: String,
+    /// number of times to play the timeline before moving on to the next entry
+    #[serde(default = "default_repeat")]
+    pub repeat: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Playlist {
+    /// playlist name
+    pub name: String,
+    /// timelines to play one after the other
+    pub entries: Vec<PlaylistEntry>,
+    /// index of the entry that is currently playing, if any
+    #[serde(skip)]
+    current: Option<usize>,
+    /// number of times the current entry has been played through
+    #[serde(skip)]
+    repetition: u32,
+    /// indication of whether the playlist progressed and an update should be published
+    #[serde(skip)]
+    update_required: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistSnapshot {
+    /// playlist name
+    pub name: String,
+    /// index of the entry that is currently playing, if any
+    pub entry: Option<usize>,
+    /// total number of entries in the playlist
+    pub entry_count: usize,
+    /// name of the timeline that is currently playing, if any
+    pub timeline: Option<String>,
+    /// number of times the current entry has been played through
+    pub repetition: u32,
+    /// whether or not the playlist is currently playing
+    pub is_playing: bool,
+}
+
+impl Playlist {
+    pub fn is_playing(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn current_entry(&self) -> Option<&PlaylistEntry> {
+        self.current.and_then(|index| self.entries.get(index))
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    /// Move to the entry at the given index, returning the name of the timeline to start.
+    /// Moving past the last entry stops the playlist.
+    pub fn go_to(&mut self, index: usize) -> Option<String> {
+        self.current = if index < self.entries.len() {
+            Some(index)
+        } else {
+            None
+        };
+        self.repetition = 0;
+        self.update_required = true;
+        self.current_entry().map(|entry| entry.timeline.clone())
+    }
+
+    pub fn stop(&mut self) {
+        self.current = None;
+        self.repetition = 0;
+        self.update_required = true;
+    }
+
+    /// Register that the timeline of the current entry has finished playing, returning the name
+    /// of the timeline to start next. This is the same timeline if the entry should be repeated.
+    pub fn advance(&mut self) -> Option<String> {
+        let index = self.current?;
+        self.repetition += 1;
+        if self.repetition < self.entries[index].repeat {
+            self.update_required = true;
+            self.current_entry().map(|entry| entry.timeline.clone())
+        } else {
+            self.go_to(index + 1)
+        }
+    }
+
+    /// Take over the progress of another playlist, e.g. an older version of this one
+    pub fn copy_progress(&mut self, src: &Playlist) {
+        if let Some(index) = src.current {
+            if index < self.entries.len() {
+                self.current = Some(index);
+                self.repetition = src.repetition;
+            }
+        }
+        self.update_required = true;
+    }
+
+    /// Get a snapshot of the playlist progress, if it changed since the last one
+    pub fn update(&mut self) -> Option<PlaylistSnapshot> {
+        if self.update_required {
+            self.update_required = false;
+            Some(PlaylistSnapshot {
+                name: self.name.clone(),
+                entry: self.current,
+                entry_count: self.entries.len(),
+                timeline: self.current_entry().map(|entry| entry.timeline.clone()),
+                repetition: self.repetition,
+                is_playing: self.is_playing(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(entries: &[(&str, u32)]) -> Playlist {
+        Playlist {
+            name: String::from("test"),
+            entries: entries
+                .iter()
+                .map(|(timeline, repeat)| PlaylistEntry {
+                    timeline: String::from(*timeline),
+                    repeat: *repeat,
+                })
+                .collect(),
+            current: None,
+            repetition: 0,
+            update_required: false,
+        }
+    }
+
+    #[test]
+    fn advance_repeats_entries() {
+        let mut playlist = playlist(&[("a", 2), ("b", 1)]);
+        assert_eq!(playlist.advance(), None);
+        assert_eq!(playlist.go_to(0).as_deref(), Some("a"));
+        assert_eq!(playlist.advance().as_deref(), Some("a"));
+        assert_eq!(playlist.update().unwrap().repetition, 1);
+        assert_eq!(playlist.advance().as_deref(), Some("b"));
+        let snapshot = playlist.update().unwrap();
+        assert_eq!(snapshot.entry, Some(1));
+        assert_eq!(snapshot.repetition, 0);
+        assert!(playlist.update().is_none());
+        assert_eq!(playlist.advance(), None);
+        assert!(!playlist.is_playing());
+        assert!(!playlist.update().unwrap().is_playing);
+    }
+
+    #[test]
+    fn go_to_past_last_entry_stops() {
+        let mut playlist = playlist(&[("a", 1), ("b", 3)]);
+        assert_eq!(playlist.go_to(1).as_deref(), Some("b"));
+        playlist.advance();
+        assert_eq!(playlist.go_to(2), None);
+        assert!(!playlist.is_playing());
+        let snapshot = playlist.update().unwrap();
+        assert_eq!(snapshot.entry, None);
+        assert_eq!(snapshot.timeline, None);
+        assert_eq!(snapshot.repetition, 0);
+    }
+
+    #[test]
+    fn copy_progress_keeps_entries_that_still_exist() {
+        let mut old = playlist(&[("a", 1), ("b", 3)]);
+        old.go_to(1);
+        old.advance();
+
+        let mut shorter = playlist(&[("a", 1)]);
+        shorter.copy_progress(&old);
+        assert!(!shorter.is_playing());
+
+        let mut edited = playlist(&[("a", 1), ("c", 3)]);
+        edited.copy_progress(&old);
+        assert_eq!(edited.current_index(), Some(1));
+        assert_eq!(edited.current_entry().unwrap().timeline, "c");
+        let snapshot = edited.update().unwrap();
+        assert_eq!(snapshot.repetition, 1);
+        assert_eq!(edited.advance().as_deref(), Some("c"));
+        assert_eq!(edited.advance(), None);
+    }
+}
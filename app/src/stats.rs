@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Running totals of the intervals between frames of a single timeline
+#[derive(Debug, Default)]
+struct FrameIntervals {
+    last_frame_at: Option<Instant>,
+    count: u32,
+    sum: f64,
+    sum_of_squares: f64,
+    max: f64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FrameStatsReport {
+    /// timeline name
+    pub timeline: String,
+    /// average number of frames per second
+    pub fps: f64,
+    /// standard deviation of the time between frames, in milliseconds
+    pub jitter_ms: f64,
+    /// longest time between two frames, in milliseconds
+    pub max_interval_ms: f64,
+}
+
+/// Keeps track of when playing timelines produce frames, and periodically reports on the frame
+/// rate and jitter achieved for each of them
+#[derive(Debug)]
+pub struct FrameStats {
+    interval: Duration,
+    reported_at: Instant,
+    timelines: HashMap<String, FrameIntervals>,
+}
+
+impl FrameStats {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            reported_at: Instant::now(),
+            timelines: HashMap::new(),
+        }
+    }
+
+    /// Register that the named timeline produced a frame at the given time
+    pub fn record(&mut self, timeline: &str, at: Instant) {
+        let intervals = self.timelines.entry(String::from(timeline)).or_default();
+        if let Some(last_frame_at) = intervals.last_frame_at {
+            let ms = at.duration_since(last_frame_at).as_secs_f64() * 1000.0;
+            intervals.count += 1;
+            intervals.sum += ms;
+            intervals.sum_of_squares += ms * ms;
+            intervals.max = intervals.max.max(ms);
+        }
+        intervals.last_frame_at = Some(at);
+    }
+
+    /// Stop measuring the interval since the last frame of the named timeline, e.g. because it
+    /// stopped playing
+    pub fn forget(&mut self, timeline: &str) {
+        if let Some(intervals) = self.timelines.get_mut(timeline) {
+            intervals.last_frame_at = None;
+        }
+    }
+
+    /// Get a report for each timeline that produced frames since the previous report, once the
+    /// reporting interval has passed
+    pub fn report(&mut self) -> Option<Vec<FrameStatsReport>> {
+        if self.reported_at.elapsed() < self.interval {
+            return None;
+        }
+        self.reported_at = Instant::now();
+        let reports = self
+            .timelines
+            .drain()
+            .filter(|(_, intervals)| intervals.count > 0)
+            .map(|(timeline, intervals)| {
+                let count = f64::from(intervals.count);
+                let mean = intervals.sum / count;
+                let variance = (intervals.sum_of_squares / count - mean * mean).max(0.0);
+                FrameStatsReport {
+                    timeline,
+                    fps: 1000.0 / mean,
+                    jitter_ms: variance.sqrt(),
+                    max_interval_ms: intervals.max,
+                }
+            })
+            .collect();
+        Some(reports)
+    }
+}
@@ -1,15 +1,26 @@
-use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use log::{debug, error, info};
 use rmp_serde::to_vec_named;
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tether_agent::{PlugDefinition, PlugOptionsBuilder, TetherAgent, TetherAgentOptionsBuilder};
 
 use crate::{MutexWrapper, ARGS};
 
 use crate::model::Model;
-use crate::timeline::{EventSnapshot, Timeline, TimelineSnapshot};
+use crate::playlist::{Playlist, PlaylistSnapshot};
+use crate::stats::FrameStatsReport;
+use crate::timecode::SeekTarget;
+use crate::timeline::{
+    BeatSnapshot, EventPhase, EventSnapshot, EventTrigger, Marker, Timeline, TimelineSnapshot,
+};
+
+/// Longest time to wait for outgoing status messages before checking for incoming messages again.
+/// The Tether agent can only be polled for incoming messages, so this determines their latency.
+const POLL_INTERVAL: Duration = Duration::from_millis(2);
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -21,14 +32,63 @@ struct MessagePayloadState {
 #[derive(Deserialize)]
 struct MessagePayloadSeek {
     timeline: String,
-    position: f64,
+    /// normalized position, SMPTE timecode or bar:beat address
+    position: SeekTarget,
+}
+
+#[derive(Deserialize)]
+struct MessagePayloadStep {
+    timeline: String,
+    frames: i64,
+}
+
+#[derive(Deserialize)]
+struct MessagePayloadRate {
+    timeline: String,
+    rate: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessagePayloadRegion {
+    timeline: String,
+    in_point: f64,
+    out_point: f64,
+}
+
+#[derive(Deserialize)]
+struct MessagePayloadMarker {
+    timeline: String,
+    marker: String,
+}
+
+#[derive(Serialize)]
+struct MessagePayloadMarkers<'a> {
+    timeline: &'a str,
+    markers: &'a [Marker],
 }
 
 pub enum ControlMessage {
     Select(String),
     Play(String),
-    Stop,
-    Seek(String, f64),
+    /// Pause the named timeline, or the one selected in the UI if no name is given
+    Pause(Option<String>),
+    Stop(String),
+    Resume(String),
+    Seek(String, SeekTarget),
+    Step(String, i64),
+    Rate(String, f64),
+    SetRegion(String, f64, f64),
+    ClearRegion(String),
+    ReleaseRegion(String),
+    SeekMarker(String, String),
+    PlayToMarker(String, String),
+    ListMarkers(String),
+    UpdatePlaylists(Vec<Playlist>),
+    PlayPlaylist(String),
+    NextInPlaylist(String),
+    PreviousInPlaylist(String),
+    StopPlaylist(String),
     Update(Vec<Timeline>, String),
 }
 
@@ -38,27 +98,148 @@ pub enum StatusMessage {
     /// Update message containing the current timecode in ms, the normalized playback position,
     /// the current timeline name, and a list of tracks with their name and current value (if any)
     Update(TimelineSnapshot),
+    /// Playlist progress, i.e. which entry and timeline are currently playing
+    Playlist(PlaylistSnapshot),
+    /// Frame rate and jitter achieved for each playing timeline
+    Stats(Vec<FrameStatsReport>),
+    /// List of markers on the timeline with the given name
+    Markers(String, Vec<Marker>),
     /// Triggered event with timeline name, name of the track that contains the event,
     /// and the event name
     Event(EventSnapshot),
 }
 
+/// Function that turns the payload of a message received on the named plug into a control
+/// message, if it is valid
+type InputHandler = fn(&str, &[u8]) -> Option<ControlMessage>;
+
+/// Input plugs, each subscribed to the UI topic of the same name
+const INPUTS: &[(&str, InputHandler)] = &[
+    // State changes received, i.e. new timeline config data came in
+    ("state", |plug, payload| {
+        decode(plug, payload).map(|state: MessagePayloadState| {
+            ControlMessage::Update(state.timelines, state.selected_timeline)
+        })
+    }),
+    // new timeline selected
+    ("select", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::Select)
+    }),
+    // play request received for timeline
+    ("play", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::Play)
+    }),
+    // pause request received; older UI versions don't specify a timeline
+    ("pause", |plug, payload| {
+        if payload.is_empty() {
+            Some(ControlMessage::Pause(None))
+        } else {
+            decode(plug, payload).map(|timeline| ControlMessage::Pause(Some(timeline)))
+        }
+    }),
+    // stop request received for timeline
+    ("stop", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::Stop)
+    }),
+    // resume request received for timeline
+    ("resume", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::Resume)
+    }),
+    // seek request received for timeline
+    ("seek", |plug, payload| {
+        decode(plug, payload)
+            .map(|seek: MessagePayloadSeek| ControlMessage::Seek(seek.timeline, seek.position))
+    }),
+    // frame step request received for timeline
+    ("step", |plug, payload| {
+        decode(plug, payload)
+            .map(|step: MessagePayloadStep| ControlMessage::Step(step.timeline, step.frames))
+    }),
+    // playback rate change requested for timeline
+    ("rate", |plug, payload| {
+        decode(plug, payload)
+            .map(|rate: MessagePayloadRate| ControlMessage::Rate(rate.timeline, rate.rate))
+    }),
+    // loop region set for timeline
+    ("region", |plug, payload| {
+        decode(plug, payload).map(|region: MessagePayloadRegion| {
+            ControlMessage::SetRegion(region.timeline, region.in_point, region.out_point)
+        })
+    }),
+    // loop region cleared for timeline
+    ("clearRegion", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::ClearRegion)
+    }),
+    // loop region released for timeline, letting playback continue to the end
+    ("releaseRegion", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::ReleaseRegion)
+    }),
+    // seek to marker requested for timeline
+    ("seekMarker", |plug, payload| {
+        decode(plug, payload).map(|marker: MessagePayloadMarker| {
+            ControlMessage::SeekMarker(marker.timeline, marker.marker)
+        })
+    }),
+    // playback until marker requested for timeline
+    ("playToMarker", |plug, payload| {
+        decode(plug, payload).map(|marker: MessagePayloadMarker| {
+            ControlMessage::PlayToMarker(marker.timeline, marker.marker)
+        })
+    }),
+    // list of markers requested for timeline
+    ("listMarkers", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::ListMarkers)
+    }),
+    // playlist definitions received
+    ("playlists", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::UpdatePlaylists)
+    }),
+    // play request received for playlist
+    ("playlistPlay", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::PlayPlaylist)
+    }),
+    // skip to next entry requested for playlist
+    ("playlistNext", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::NextInPlaylist)
+    }),
+    // skip to previous entry requested for playlist
+    ("playlistPrevious", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::PreviousInPlaylist)
+    }),
+    // stop request received for playlist
+    ("playlistStop", |plug, payload| {
+        decode(plug, payload).map(ControlMessage::StopPlaylist)
+    }),
+];
+
+/// Decode the payload of a message received on the given plug, logging an error if it is invalid
+fn decode<T: DeserializeOwned>(plug: &str, payload: &[u8]) -> Option<T> {
+    match rmp_serde::from_slice::<T>(payload) {
+        Ok(value) => Some(value),
+        Err(err) => {
+            error!("Could not decode payload from '{}' message. {}", plug, err);
+            None
+        }
+    }
+}
+
 pub struct Tether {
-    tx: SyncSender<ControlMessage>,
+    tx: Sender<ControlMessage>,
     rx: Receiver<StatusMessage>,
     agent: TetherAgent,
-    input_state: PlugDefinition,
-    input_select_timeline: PlugDefinition,
-    input_play: PlugDefinition,
-    input_pause: PlugDefinition,
-    input_seek: PlugDefinition,
+    /// input plugs, along with the function that turns their messages into control messages
+    inputs: Vec<(PlugDefinition, InputHandler)>,
     output_state: PlugDefinition,
     output_update: PlugDefinition,
     output_event: PlugDefinition,
+    output_beat: PlugDefinition,
+    output_markers: PlugDefinition,
+    output_playlist: PlugDefinition,
+    output_stats: PlugDefinition,
 }
 
 impl Tether {
-    pub fn new(tx: SyncSender<ControlMessage>, rx: Receiver<StatusMessage>) -> Self {
+    pub fn new(tx: Sender<ControlMessage>, rx: Receiver<StatusMessage>) -> Self {
         let mut builder = TetherAgentOptionsBuilder::new("tether-timeline");
         if let Some(agent_id) = &ARGS.tether_agent_id {
             builder = builder.id(agent_id.as_str());
@@ -74,69 +255,72 @@ impl Tether {
         }
         let agent = builder.build().expect("Failed to initialize Tether agent");
 
-        let input_state = PlugOptionsBuilder::create_input("state")
-            .topic("tether-timeline-ui/+/state")
-            .qos(2)
-            .build(&agent)
-            .expect("Could not create input plug 'state'");
+        let inputs = INPUTS
+            .iter()
+            .map(|(name, handler)| {
+                let plug = PlugOptionsBuilder::create_input(name)
+                    // TODO generic subscription, but with specific enough plug name somehow
+                    .topic(format!("tether-timeline-ui/+/{}", name).as_str())
+                    .qos(2)
+                    .build(&agent)
+                    .unwrap_or_else(|err| {
+                        panic!("Could not create input plug '{}'. {}", name, err)
+                    });
+                (plug, *handler)
+            })
+            .collect();
 
-        let input_select_timeline = PlugOptionsBuilder::create_input("select")
-            .topic("tether-timeline-ui/+/select")
+        let output_state = PlugOptionsBuilder::create_output("state")
             .qos(2)
+            .retain(true)
             .build(&agent)
-            .expect("Could not create input plug 'select'");
+            .expect("Could not create output plug 'state'");
 
-        let input_play = PlugOptionsBuilder::create_input("play")
-            // TODO generic subscription, but with specific enough plug name somehow
-            .topic("tether-timeline-ui/+/play")
+        let output_update = PlugOptionsBuilder::create_output("update")
             .qos(2)
+            .retain(true)
             .build(&agent)
-            .expect("Could not create input plug 'play'");
+            .expect("Could not create output plug 'update'");
 
-        let input_pause = PlugOptionsBuilder::create_input("pause")
-            // TODO generic subscription, but with specific enough plug name somehow
-            .topic("tether-timeline-ui/+/pause")
+        let output_event = PlugOptionsBuilder::create_output("event")
             .qos(2)
+            .retain(true)
             .build(&agent)
-            .expect("Could not create input plug 'pause'");
+            .expect("Could not create output plug 'event'");
 
-        let input_seek = PlugOptionsBuilder::create_input("seek")
-            // TODO generic subscription, but with specific enough plug name somehow
-            .topic("tether-timeline-ui/+/seek")
+        let output_beat = PlugOptionsBuilder::create_output("beat")
             .qos(2)
             .build(&agent)
-            .expect("Could not create input plug 'seek'");
+            .expect("Could not create output plug 'beat'");
 
-        let output_state = PlugOptionsBuilder::create_output("state")
+        let output_markers = PlugOptionsBuilder::create_output("markers")
             .qos(2)
-            .retain(true)
             .build(&agent)
-            .expect("Could not create output plug 'state'");
+            .expect("Could not create output plug 'markers'");
 
-        let output_update = PlugOptionsBuilder::create_output("update")
+        let output_playlist = PlugOptionsBuilder::create_output("playlist")
             .qos(2)
             .retain(true)
             .build(&agent)
-            .expect("Could not create output plug 'update'");
+            .expect("Could not create output plug 'playlist'");
 
-        let output_event = PlugOptionsBuilder::create_output("event")
-            .qos(2)
-            .retain(true)
+        let output_stats = PlugOptionsBuilder::create_output("stats")
+            .qos(0)
             .build(&agent)
-            .expect("Could not create output plug 'event'");
+            .expect("Could not create output plug 'stats'");
 
         Self {
             tx,
             rx,
             agent,
-            input_state,
-            input_select_timeline,
-            input_play,
-            input_pause,
-            input_seek,
+            inputs,
             output_state,
             output_update,
             output_event,
+            output_beat,
+            output_markers,
+            output_playlist,
+            output_stats,
         }
     }
 
@@ -144,64 +328,20 @@ impl Tether {
         let tx = self.tx.clone();
 
         loop {
-            if let Some((plug_name, message)) = self.agent.check_messages() {
+            while let Some((plug_name, message)) = self.agent.check_messages() {
                 debug!("Message received on plug '{}'", &plug_name);
-                // State changes received, i.e. new timeline config data came in
-                if plug_name.as_str().eq(self.input_state.name()) {
-                    match rmp_serde::from_slice::<MessagePayloadState>(message.payload()) {
-                        Ok(state) => {
-                            tx.send(ControlMessage::Update(
-                                state.timelines,
-                                state.selected_timeline,
-                            ))
-                            .ok();
-                        }
-                        Err(err) => {
-                            error!("Could not decode payload from 'state' message. {}", err);
-                        }
-                    }
-                }
-                // new timeline selected
-                else if plug_name.as_str().eq(self.input_select_timeline.name()) {
-                    match rmp_serde::from_slice::<String>(message.payload()) {
-                        Ok(timeline) => {
-                            tx.send(ControlMessage::Select(timeline)).ok();
-                        }
-                        Err(err) => {
-                            error!("Could not decode payload from 'play' message. {}", err);
-                        }
-                    }
-                }
-                // play request received for timeline
-                else if plug_name.as_str().eq(self.input_play.name()) {
-                    match rmp_serde::from_slice::<String>(message.payload()) {
-                        Ok(timeline) => {
-                            tx.send(ControlMessage::Play(timeline)).ok();
-                        }
-                        Err(err) => {
-                            error!("Could not decode payload from 'play' message. {}", err);
-                        }
-                    }
-                }
-                // stop request received
-                else if plug_name.as_str().eq(self.input_pause.name()) {
-                    tx.send(ControlMessage::Stop).ok();
-                }
-                // seek request received for timeline
-                else if plug_name.as_str().eq(self.input_seek.name()) {
-                    match rmp_serde::from_slice::<MessagePayloadSeek>(message.payload()) {
-                        Ok(payload) => {
-                            tx.send(ControlMessage::Seek(payload.timeline, payload.position))
-                                .ok();
-                        }
-                        Err(err) => {
-                            error!("Could not decode payload from 'seek' message. {}", err);
-                        }
+                if let Some((_, handler)) = self
+                    .inputs
+                    .iter()
+                    .find(|(plug, _)| plug_name.as_str().eq(plug.name()))
+                {
+                    if let Some(control_message) = handler(plug_name.as_str(), message.payload()) {
+                        tx.send(control_message).ok();
                     }
                 }
             }
 
-            match self.rx.try_recv() {
+            match self.rx.recv_timeout(POLL_INTERVAL) {
                 // received request to publish current overall state
                 Ok(StatusMessage::State(model)) => match to_vec_named(&model) {
                     Ok(payload) => match self.agent.publish(&self.output_state, Some(&payload)) {
@@ -222,7 +362,17 @@ impl Tether {
                 }
                 // received request to publish single event
                 Ok(StatusMessage::Event(payload)) => self.publish_event(&payload),
-                Err(_) => (),
+                // received request to publish frame rate statistics
+                Ok(StatusMessage::Stats(payload)) => self.publish_stats(&payload),
+                // received request to publish playlist progress
+                Ok(StatusMessage::Playlist(payload)) => self.publish_playlist(&payload),
+                // received request to publish the markers of a timeline
+                Ok(StatusMessage::Markers(timeline, markers)) => {
+                    self.publish_markers(&timeline, &markers)
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                // the main thread is gone, so there's nothing left to publish
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(POLL_INTERVAL),
             }
         }
     }
@@ -242,21 +392,104 @@ impl Tether {
                 error!("Could not encode timeline data payload. {}", err);
             }
         }
-        if timeline.is_playing {
-            // for each event that has occurred in each track in this update, publish a separate message as well
-            timeline.tracks.iter().for_each(|track| {
-                if let Some(events) = &track.events {
-                    events.iter().for_each(|event| {
-                        self.publish_event(&EventSnapshot {
-                            timeline: timeline.name.clone(),
-                            track: track.name.clone(),
-                            position: event.position,
-                            time: event.position * timeline.duration,
-                            data: event.data.clone(),
-                        });
-                    });
-                }
+        if timeline.played {
+            self.publish_occurrences(timeline);
+        }
+    }
+
+    /// Publish a separate message for each event and beat that occurred in this update, including
+    /// those of any nested timelines
+    fn publish_occurrences(&self, timeline: &TimelineSnapshot) {
+        timeline.tracks.iter().for_each(|track| {
+            let publish = |event: &EventTrigger, phase: EventPhase| {
+                let position = match phase {
+                    EventPhase::End => event.get_end().unwrap_or(event.position),
+                    _ => event.position,
+                };
+                self.publish_event(&EventSnapshot {
+                    timeline: timeline.name.clone(),
+                    track: track.name.clone(),
+                    position: event.position,
+                    time: position * timeline.duration,
+                    length: event.length,
+                    phase,
+                    data: event.data.clone(),
+                });
+            };
+            if let Some(events) = &track.events {
+                events.iter().for_each(|event| {
+                    if event.length.is_some() {
+                        publish(event, EventPhase::Start);
+                    } else {
+                        publish(event, EventPhase::Instant);
+                    }
+                });
+            }
+            if let Some(events) = &track.ended_events {
+                events
+                    .iter()
+                    .for_each(|event| publish(event, EventPhase::End));
+            }
+            if let Some(nested) = &track.timeline {
+                self.publish_occurrences(nested);
+            }
+        });
+        timeline.beats.iter().for_each(|beat| {
+            self.publish_beat(&BeatSnapshot {
+                timeline: timeline.name.clone(),
+                bar: beat.bar,
+                beat: beat.beat,
+                is_downbeat: beat.beat == 1,
+                time: beat.time,
             });
+        });
+    }
+
+    fn publish_stats(&self, stats: &[FrameStatsReport]) {
+        match to_vec_named(stats) {
+            Ok(payload) => match self.agent.publish(&self.output_stats, Some(&payload)) {
+                Ok(()) => {
+                    debug!("Published frame rate statistics to Tether: {:?}", &payload)
+                }
+                Err(err) => {
+                    error!("Error publishing frame rate statistics to Tether: {}", err)
+                }
+            },
+            Err(err) => {
+                error!("Could not encode frame rate statistics payload. {}", err);
+            }
+        }
+    }
+
+    fn publish_playlist(&self, playlist: &PlaylistSnapshot) {
+        match to_vec_named(playlist) {
+            Ok(payload) => match self.agent.publish(&self.output_playlist, Some(&payload)) {
+                Ok(()) => {
+                    debug!("Published playlist progress to Tether: {:?}", &payload)
+                }
+                Err(err) => {
+                    error!("Error publishing playlist progress to Tether: {}", err)
+                }
+            },
+            Err(err) => {
+                error!("Could not encode playlist progress payload. {}", err);
+            }
+        }
+    }
+
+    fn publish_markers(&self, timeline: &str, markers: &[Marker]) {
+        match to_vec_named(&MessagePayloadMarkers { timeline, markers }) {
+            Ok(payload) => match self.agent.publish(&self.output_markers, Some(&payload)) {
+                Ok(()) => {
+                    debug!("Published markers to Tether: {:?}", &payload)
+                }
+                Err(err) => {
+                    error!("Error publishing markers to Tether: {}", err)
+                }
+            },
+            Err(err) => {
+                error!("Could not encode markers payload. {}", err);
+            }
         }
     }
 
@@ -276,4 +509,20 @@ impl Tether {
             }
         }
     }
+
+    fn publish_beat(&self, data: &BeatSnapshot) {
+        match to_vec_named(&data) {
+            Ok(payload) => match self.agent.publish(&self.output_beat, Some(&payload)) {
+                Ok(()) => {
+                    debug!("Published beat to Tether: {:?}", &payload)
+                }
+                Err(err) => {
+                    error!("Error publishing beat to Tether: {}", err)
+                }
+            },
+            Err(err) => {
+                error!("Could not encode beat payload. {}", err);
+            }
+        }
+    }
 }
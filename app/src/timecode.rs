@@ -0,0 +1,316 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::timeline::{InvalidDataError, Result};
+
+/// Resolution of musical time within a beat
+pub const TICKS_PER_BEAT: u32 = 480;
+
+/// Tolerance for musical time, so that rounding errors don't put a beat that was hit exactly at
+/// the end of the previous one
+const BEAT_EPSILON: f64 = 1e-9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSignature {
+    /// number of beats in a bar
+    pub beats_per_bar: u32,
+    /// note value of a single beat, e.g. 4 for quarter notes
+    pub beat_unit: u32,
+}
+
+impl Default for TimeSignature {
+    fn default() -> Self {
+        Self {
+            beats_per_bar: 4,
+            beat_unit: 4,
+        }
+    }
+}
+
+/// Musical tempo of a timeline, which is assumed to start on the first beat of the first bar
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tempo {
+    /// beats per minute
+    pub bpm: f64,
+    #[serde(default)]
+    pub time_signature: TimeSignature,
+    /// number of grid lines per beat to snap event positions to when timelines are updated, if any
+    #[serde(default)]
+    pub quantize: Option<u32>,
+}
+
+/// Beat that was hit by the playhead
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Beat {
+    pub bar: u32,
+    pub beat: u32,
+    /// time of the beat in seconds
+    pub time: f64,
+}
+
+/// Position in musical time. Bars and beats count from 1, ticks count from 0.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct MusicalTime {
+    pub bar: u32,
+    pub beat: u32,
+    pub tick: u32,
+}
+
+impl Tempo {
+    pub fn is_valid(&self) -> bool {
+        self.bpm.is_finite() && self.bpm > 0.0 && self.time_signature.beats_per_bar > 0
+    }
+
+    /// Duration of a single beat in seconds
+    pub fn beat_duration(&self) -> f64 {
+        60.0 / self.bpm
+    }
+
+    /// Get the musical time at the given time in seconds, if the tempo is valid
+    pub fn get_musical_time(&self, time: f64) -> Option<MusicalTime> {
+        if !self.is_valid() || time < 0.0 {
+            return None;
+        }
+        let beats = time / self.beat_duration() + BEAT_EPSILON;
+        let whole_beats = beats.floor() as u64;
+        let beats_per_bar = u64::from(self.time_signature.beats_per_bar);
+        Some(MusicalTime {
+            bar: (whole_beats / beats_per_bar + 1) as u32,
+            beat: (whole_beats % beats_per_bar + 1) as u32,
+            tick: ((beats.fract() * f64::from(TICKS_PER_BEAT)) as u32).min(TICKS_PER_BEAT - 1),
+        })
+    }
+
+    /// Get the beats hit when moving from one time in seconds to another, in the order they were
+    /// hit. A beat at the starting time counts as hit and one at the end time doesn't, so that
+    /// beats on the boundary between consecutive moves are only hit once.
+    pub fn get_beats_between(&self, from: f64, to: f64) -> Vec<Beat> {
+        if !self.is_valid() {
+            return Vec::new();
+        }
+        let from = from / self.beat_duration();
+        let to = to / self.beat_duration();
+        let indices: Vec<u64> = if to >= from {
+            let first = (from - BEAT_EPSILON).ceil().max(0.0) as u64;
+            let end = (to - BEAT_EPSILON).ceil().max(0.0) as u64;
+            (first..end).collect()
+        } else {
+            let first = (from + BEAT_EPSILON).floor();
+            let end = (to + BEAT_EPSILON).floor() + 1.0;
+            if first < 0.0 {
+                return Vec::new();
+            }
+            (end.max(0.0) as u64..=first as u64).rev().collect()
+        };
+        indices
+            .into_iter()
+            .map(|index| self.get_beat(index))
+            .collect()
+    }
+
+    /// Get the beat with the given index, counting from 0 at the start of the timeline
+    fn get_beat(&self, index: u64) -> Beat {
+        let beats_per_bar = u64::from(self.time_signature.beats_per_bar);
+        Beat {
+            bar: (index / beats_per_bar + 1) as u32,
+            beat: (index % beats_per_bar + 1) as u32,
+            time: index as f64 * self.beat_duration(),
+        }
+    }
+
+    /// Snap a time in seconds to the nearest line of the quantization grid, if there is one
+    pub fn quantize(&self, time: f64) -> f64 {
+        match self.quantize {
+            Some(subdivisions) if subdivisions > 0 && self.is_valid() => {
+                let grid = self.beat_duration() / f64::from(subdivisions);
+                (time / grid).round() * grid
+            }
+            _ => time,
+        }
+    }
+
+    /// Get the time in seconds of a `bar:beat` or `bar:beat:tick` address
+    pub fn parse_bar_beat(&self, address: &str) -> Result<f64> {
+        let parts = parse_parts(address)?;
+        let (bar, beat, tick) = match parts[..] {
+            [bar, beat] => (bar, beat, 0),
+            [bar, beat, tick] => (bar, beat, tick),
+            _ => return Err(InvalidDataError),
+        };
+        if !self.is_valid()
+            || bar < 1
+            || beat < 1
+            || beat > self.time_signature.beats_per_bar
+            || tick >= TICKS_PER_BEAT
+        {
+            return Err(InvalidDataError);
+        }
+        let beats = f64::from(bar - 1) * f64::from(self.time_signature.beats_per_bar)
+            + f64::from(beat - 1)
+            + f64::from(tick) / f64::from(TICKS_PER_BEAT);
+        Ok(beats * self.beat_duration())
+    }
+}
+
+/// Format a frame number as SMPTE timecode (HH:MM:SS:FF) at the given frame rate
+pub fn format_timecode(frame: u64, fps: u32) -> String {
+    let fps = u64::from(fps.max(1));
+    let seconds = frame / fps;
+    format!(
+        "{:02}:{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        frame % fps
+    )
+}
+
+/// Get the time in seconds of SMPTE timecode (HH:MM:SS:FF) at the given frame rate
+pub fn parse_timecode(timecode: &str, fps: u32) -> Result<f64> {
+    match parse_parts(timecode)?[..] {
+        [hours, minutes, seconds, frames] if minutes < 60 && seconds < 60 && frames < fps => {
+            Ok(f64::from(hours) * 3600.0
+                + f64::from(minutes * 60 + seconds)
+                + f64::from(frames) / f64::from(fps))
+        }
+        _ => Err(InvalidDataError),
+    }
+}
+
+fn parse_parts(address: &str) -> Result<Vec<u32>> {
+    address
+        .trim()
+        .split(':')
+        .map(|part| part.parse::<u32>().map_err(|_| InvalidDataError))
+        .collect()
+}
+
+/// Position to seek to, either normalized or as an address that gets resolved against the timeline
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SeekTarget {
+    /// normalized position
+    Position(f64),
+    /// SMPTE timecode (HH:MM:SS:FF), or musical time (bar:beat or bar:beat:tick)
+    Address(String),
+}
+
+impl fmt::Display for SeekTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SeekTarget::Position(position) => write!(f, "{}", position),
+            SeekTarget::Address(address) => write!(f, "{}", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 120 bpm in 3/4, so that a beat lasts half a second and a bar a second and a half
+    fn tempo() -> Tempo {
+        Tempo {
+            bpm: 120.0,
+            time_signature: TimeSignature {
+                beats_per_bar: 3,
+                beat_unit: 4,
+            },
+            quantize: None,
+        }
+    }
+
+    #[test]
+    fn timecode_round_trips() {
+        assert_eq!(format_timecode(0, 25), "00:00:00:00");
+        assert_eq!(
+            format_timecode(3 * 3600 * 25 + 62 * 25 + 24, 25),
+            "03:01:02:24"
+        );
+        assert_eq!(parse_timecode("03:01:02:24", 25).unwrap(), 10862.96);
+        assert_eq!(parse_timecode(" 00:00:01:15 ", 30).unwrap(), 1.5);
+        // a frame rate of zero is treated as one frame per second
+        assert_eq!(format_timecode(61, 0), "00:01:01:00");
+    }
+
+    #[test]
+    fn timecode_rejects_out_of_range_parts() {
+        assert!(parse_timecode("00:00:00:25", 25).is_err());
+        assert!(parse_timecode("00:00:60:00", 25).is_err());
+        assert!(parse_timecode("00:60:00:00", 25).is_err());
+        assert!(parse_timecode("00:00:00", 25).is_err());
+        assert!(parse_timecode("00:00:-1:00", 25).is_err());
+        assert!(parse_timecode("00:00:aa:00", 25).is_err());
+    }
+
+    #[test]
+    fn bar_beat_addresses_count_from_one() {
+        let tempo = tempo();
+        assert_eq!(tempo.parse_bar_beat("1:1").unwrap(), 0.0);
+        assert_eq!(tempo.parse_bar_beat("2:3").unwrap(), 2.5);
+        assert_eq!(tempo.parse_bar_beat("1:2:240").unwrap(), 0.75);
+        assert!(tempo.parse_bar_beat("0:1").is_err());
+        assert!(tempo.parse_bar_beat("1:0").is_err());
+        assert!(tempo.parse_bar_beat("1:4").is_err());
+        assert!(tempo.parse_bar_beat("1:1:480").is_err());
+        assert!(tempo.parse_bar_beat("1").is_err());
+        let invalid = Tempo { bpm: 0.0, ..tempo };
+        assert!(invalid.parse_bar_beat("1:1").is_err());
+    }
+
+    #[test]
+    fn musical_time_matches_bar_beat_addresses() {
+        let tempo = tempo();
+        assert_eq!(
+            tempo.get_musical_time(0.0),
+            Some(MusicalTime {
+                bar: 1,
+                beat: 1,
+                tick: 0
+            })
+        );
+        assert_eq!(
+            tempo.get_musical_time(2.75),
+            Some(MusicalTime {
+                bar: 2,
+                beat: 3,
+                tick: 240
+            })
+        );
+        // rounding errors don't put a beat that was hit exactly at the end of the previous one
+        assert_eq!(
+            tempo.get_musical_time(0.1 + 0.2 + 1.2),
+            Some(MusicalTime {
+                bar: 2,
+                beat: 1,
+                tick: 0
+            })
+        );
+        assert_eq!(tempo.get_musical_time(-0.5), None);
+        assert_eq!(Tempo { bpm: -1.0, ..tempo }.get_musical_time(1.0), None);
+    }
+
+    #[test]
+    fn beats_are_hit_once_in_either_direction() {
+        let tempo = tempo();
+        let beats = |from: f64, to: f64| -> Vec<(u32, u32)> {
+            tempo
+                .get_beats_between(from, to)
+                .iter()
+                .map(|beat| (beat.bar, beat.beat))
+                .collect()
+        };
+        assert_eq!(beats(0.0, 1.0), vec![(1, 1), (1, 2)]);
+        assert_eq!(beats(1.0, 2.0), vec![(1, 3), (2, 1)]);
+        assert_eq!(beats(1.1, 1.4), vec![]);
+        // backwards, the beats come in reverse order and the one at the end time isn't hit
+        assert_eq!(beats(2.0, 0.5), vec![(2, 2), (2, 1), (1, 3)]);
+        assert_eq!(beats(0.5, 0.0), vec![(1, 2)]);
+        assert_eq!(beats(1.0, -1.0), vec![(1, 3), (1, 2), (1, 1)]);
+        assert_eq!(beats(-0.2, -1.0), vec![]);
+        assert_eq!(tempo.get_beats_between(1.5, 1.0)[0].time, 1.5);
+    }
+}
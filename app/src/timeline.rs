@@ -1,19 +1,24 @@
-use std::{
-    fmt,
-    time::{Duration, SystemTime},
-};
+use std::{fmt, sync::Arc, time::Duration};
 
-use log::error;
 use serde::{Deserialize, Serialize};
 
+use crate::clock::{default_clock, Clock};
+
 use crate::color_gradient::{ColorGradient, ColorStop, Gradient};
+use crate::timecode::{format_timecode, parse_timecode, Beat, MusicalTime, SeekTarget, Tempo};
 use crate::{
-    bezier::{AnchorPoint, BezierCurve, Curve, Point2D},
+    bezier::{AnchorPoint, BezierCurve, Curve, Interpolation, Point2D},
     color_gradient::RGBFloat,
+    expression::{Expression, ExpressionError},
+    generator::Generator,
 };
 
 pub type Result<T> = std::result::Result<T, InvalidDataError>;
 
+/// Tolerance for normalized positions, so that accumulated rounding errors don't keep the
+/// playhead from reaching the end of a timeline or region
+const POSITION_EPSILON: f64 = 1e-9;
+
 #[derive(Debug, Clone)]
 pub struct InvalidDataError;
 
@@ -23,19 +28,131 @@ impl fmt::Display for InvalidDataError {
     }
 }
 
-#[derive(Debug, Default, PartialEq, Serialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
 pub enum PlayState {
-    /// Playing since time X, started from position Y
-    Playing(SystemTime, f64),
+    /// Playing, with the playhead last advanced at clock time X
+    Playing(Duration),
+    /// Paused at the current position, from where playback can be resumed
+    Paused,
     #[default]
     Stopped,
 }
 
+/// Named position on a timeline
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Marker {
+    pub name: String,
+    /// normalized position of the marker
+    pub position: f64,
+}
+
+/// Behaviour of a timeline once playback reaches its end, which is the start of the timeline when
+/// playing backwards
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum LoopMode {
+    /// play once, then stop at the end
+    #[default]
+    Once,
+    /// play once, then stop and rewind to the start
+    OnceRewind,
+    /// keep looping until stopped
+    Loop,
+    /// play the given number of times in total, then stop at the end
+    LoopCount(u32),
+    /// keep playing back and forth between start and end until stopped
+    PingPong,
+}
+
+impl LoopMode {
+    pub fn is_looping(&self) -> bool {
+        matches!(
+            self,
+            LoopMode::Loop | LoopMode::LoopCount(_) | LoopMode::PingPong
+        )
+    }
+}
+
+/// What to do with the events that the playhead skips over when seeking during playback
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum SeekEvents {
+    /// don't emit any of the skipped events
+    #[default]
+    Suppress,
+    /// emit all of the skipped events
+    Fire,
+    /// emit only the most recent event of each track, so that downstream systems end up in the
+    /// state they would have been in had the timeline played up to the new position
+    Chase,
+}
+
+/// How the keyframes of a timeline are affected by changes to its duration
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum KeyframeTiming {
+    /// keyframes stay at the same normalized position, i.e. they stretch along with the timeline
+    #[default]
+    Relative,
+    /// keyframes stay at the same time in seconds, so extending the timeline adds time at the end
+    /// and shortening it leaves any keyframes past the new end out of playback until the timeline
+    /// is extended again
+    Absolute,
+}
+
+/// Stretch of a timeline that was passed by the playhead during a single update, from one
+/// normalized position to another. When playing backwards, `to` is smaller than `from`.
+/// The end of a span is always part of it, but its start only if the playhead didn't get there
+/// at the end of the previous span, so that the position where two spans meet is passed once.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    pub from: f64,
+    pub to: f64,
+    /// whether `from` is part of this span, which is the case when playback starts or the
+    /// playhead jumped there
+    pub includes_from: bool,
+}
+
+impl Span {
+    /// Span without any length, i.e. the playhead standing still at the given position
+    pub fn at(position: f64) -> Self {
+        Self {
+            from: position,
+            to: position,
+            includes_from: true,
+        }
+    }
+
+    pub fn contains(&self, position: f64) -> bool {
+        if position == self.from && !self.includes_from {
+            false
+        } else if self.to >= self.from {
+            (self.from..=self.to).contains(&position)
+        } else {
+            (self.to..=self.from).contains(&position)
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct EventTrigger {
     pub id: String,
     pub position: f64,
-    pub data: String,
+    /// normalized length of the event, if it lasts for a while rather than happening at a moment
+    #[serde(default)]
+    pub length: Option<f64>,
+    /// structured event data, which may also be a plain string as in older data
+    #[serde(default)]
+    pub data: serde_json::Value,
+}
+
+/// Stage of an event that an event message is about
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventPhase {
+    /// event without a length, which happens at a single moment
+    Instant,
+    /// playhead entered an event with a length
+    Start,
+    /// playhead left an event with a length
+    End,
 }
 
 #[derive(Debug, Serialize)]
@@ -48,16 +165,74 @@ pub struct EventSnapshot {
     pub position: f64,
     /// time at which the event occurred
     pub time: f64,
+    /// normalized length of the event, if any
+    pub length: Option<f64>,
+    /// whether the event happened at a moment, or the playhead entered or left it
+    pub phase: EventPhase,
     /// event data
-    pub data: String,
+    pub data: serde_json::Value,
 }
 
 impl EventTrigger {
-    fn occurred_between(&self, prev: f64, cur: f64) -> bool {
-        cur == self.position
-            || (cur > prev && (prev..cur).contains(&self.position))
-            || (cur < prev && (self.position > prev || self.position < cur))
+    /// Whether the event was passed by the playhead in any of the given spans, in either direction
+    fn occurred_between(&self, spans: &[Span]) -> bool {
+        spans.iter().any(|span| span.contains(self.position))
+    }
+
+    /// Normalized end position of an event with a length, limited to the end of the timeline
+    pub fn get_end(&self) -> Option<f64> {
+        self.length
+            .map(|length| (self.position + length.max(0.0)).min(1.0))
     }
+
+    /// Whether the playhead entered the event in any of the given spans. That is where it passed
+    /// the start of the event when playing forwards, or its end when playing backwards.
+    fn started_between(&self, spans: &[Span]) -> bool {
+        match self.get_end() {
+            Some(end) => spans.iter().any(|span| {
+                span.contains(if span.to >= span.from {
+                    self.position
+                } else {
+                    end
+                })
+            }),
+            None => self.occurred_between(spans),
+        }
+    }
+
+    /// Whether the playhead left an event with a length in any of the given spans
+    fn ended_between(&self, spans: &[Span]) -> bool {
+        self.get_end().is_some_and(|end| {
+            spans.iter().any(|span| {
+                span.contains(if span.to >= span.from {
+                    end
+                } else {
+                    self.position
+                })
+            })
+        })
+    }
+
+    /// Whether the given position lies within an event with a length
+    pub fn is_active_at(&self, position: f64) -> bool {
+        self.get_end()
+            .is_some_and(|end| self.position <= position && position < end)
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BeatSnapshot {
+    /// timeline name
+    pub timeline: String,
+    /// bar number, counting from 1
+    pub bar: u32,
+    /// beat number within the bar, counting from 1
+    pub beat: u32,
+    /// whether this is the first beat of a bar
+    pub is_downbeat: bool,
+    /// time of the beat in seconds
+    pub time: f64,
 }
 
 #[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
@@ -66,9 +241,90 @@ pub enum TrackMode {
     Curve,
     Event,
     Color,
+    Timeline,
+    Step,
+    Vector,
+    State,
+    Generator,
+    Expression,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Discrete state that holds from its position until the next keyframe
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StateKeyframe {
+    pub position: f64,
+    pub state: String,
+}
+
+/// Shape of the mapping from normalized track values onto an output range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Response {
+    #[default]
+    Linear,
+    /// rises quickly at first, then levels off
+    Logarithmic,
+    /// rises slowly at first, then speeds up
+    Exponential,
+}
+
+impl Response {
+    /// Apply the response to a normalized value. Logarithmic and exponential responses only
+    /// apply to the range 0..1, so values outside of it are clamped for those.
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            Response::Linear => value,
+            Response::Logarithmic => (1.0 + 9.0 * value.clamp(0.0, 1.0)).log10(),
+            Response::Exponential => (10.0_f64.powf(value.clamp(0.0, 1.0)) - 1.0) / 9.0,
+        }
+    }
+}
+
+/// Range and units to map the normalized values of a track onto before publishing them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputMapping {
+    /// output value for a normalized value of 0
+    pub min: f64,
+    /// output value for a normalized value of 1
+    pub max: f64,
+    #[serde(default)]
+    pub response: Response,
+    /// label of the units of the output values, if any
+    #[serde(default)]
+    pub units: Option<String>,
+}
+
+impl OutputMapping {
+    pub fn map(&self, value: f64) -> f64 {
+        self.min + (self.max - self.min) * self.response.apply(value)
+    }
+}
+
+/// Value that holds from its position until the next keyframe
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct StepKeyframe {
+    pub position: f64,
+    pub value: f64,
+}
+
+fn default_time_scale() -> f64 {
+    1.0
+}
+
+/// Reference from a track to another timeline that plays within it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NestedTimeline {
+    /// name of the nested timeline
+    pub name: String,
+    /// time in seconds on the parent timeline at which the nested timeline starts
+    #[serde(default)]
+    pub offset: f64,
+    /// playback speed of the nested timeline relative to the parent timeline
+    #[serde(default = "default_time_scale")]
+    pub time_scale: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Track {
     /// track name
     pub name: String,
@@ -80,53 +336,146 @@ pub struct Track {
     pub events: Option<Vec<EventTrigger>>,
     /// color gradient
     pub colors: Option<ColorGradient>,
+    /// nested timeline
+    #[serde(default)]
+    pub timeline: Option<NestedTimeline>,
+    /// values that hold until the next keyframe
+    #[serde(default)]
+    pub steps: Option<Vec<StepKeyframe>>,
+    /// separate curve for each component of a vector
+    #[serde(default)]
+    pub components: Option<Vec<BezierCurve>>,
+    /// range and units to map values onto, if any
+    #[serde(default)]
+    pub output: Option<OutputMapping>,
+    /// discrete states that hold until the next keyframe
+    #[serde(default)]
+    pub states: Option<Vec<StateKeyframe>>,
+    /// procedural source of values
+    #[serde(default)]
+    pub generator: Option<Generator>,
+    /// math expression over the values of other tracks
+    #[serde(default)]
+    pub expression: Option<String>,
+    /// expression as parsed when the timeline data was last updated, if it was valid
+    #[serde(skip)]
+    pub parsed_expression: Option<Expression>,
 }
 
 #[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TrackSnapshot {
     /// track name
     pub name: String,
     /// curve value at snapshot position, if any
     pub value: Option<f64>,
-    /// events at snapshot position, if any
+    /// vector component values at snapshot position, if any
+    pub values: Option<Vec<Option<f64>>>,
+    /// value mapped onto the output range of the track, if any
+    pub mapped_value: Option<f64>,
+    /// vector component values mapped onto the output range of the track, if any
+    pub mapped_values: Option<Vec<Option<f64>>>,
+    /// output range and units of the track, if any
+    pub output: Option<OutputMapping>,
+    /// state at snapshot position, if any
+    pub state: Option<String>,
+    /// events that happened or started since the previous snapshot, if any
     pub events: Option<Vec<EventTrigger>>,
+    /// events with a length that ended since the previous snapshot, if any
+    pub ended_events: Option<Vec<EventTrigger>>,
+    /// events with a length that the snapshot position lies within, if any
+    pub active_events: Option<Vec<EventTrigger>>,
     /// color at snapshot position, if any
     pub color: Option<RGBFloat>,
+    /// snapshot of the nested timeline at snapshot position, if any
+    pub timeline: Option<Box<TimelineSnapshot>>,
+}
+
+impl TrackSnapshot {
+    /// Get the value of the track, or one of its components such as `a` for colors or `x` for
+    /// vectors
+    pub fn get_component(&self, component: Option<&str>) -> Option<f64> {
+        match (component, &self.color, &self.values) {
+            (None, _, _) => self.value,
+            (Some(component), Some(color), _) => match component {
+                "r" => Some(color.r),
+                "g" => Some(color.g),
+                "b" => Some(color.b),
+                "a" => Some(color.a),
+                _ => None,
+            },
+            (Some(component), None, Some(values)) => get_component_index(component)
+                .and_then(|index| values.get(index).copied().flatten()),
+            _ => None,
+        }
+    }
+}
+
+/// Get the index of a vector component, given either as x, y, z or w, or as a number
+fn get_component_index(component: &str) -> Option<usize> {
+    match component {
+        "x" => Some(0),
+        "y" => Some(1),
+        "z" => Some(2),
+        "w" => Some(3),
+        _ => component.parse().ok(),
+    }
+}
+
+/// Flat curve halfway up, for new tracks
+fn default_curve() -> BezierCurve {
+    vec![
+        AnchorPoint {
+            anchor: Point2D { x: 0.0, y: 0.5 },
+            control_1: Point2D { x: 0.0, y: 0.5 },
+            control_2: Point2D { x: 0.2, y: 0.5 },
+            interpolation: Interpolation::Bezier,
+        },
+        AnchorPoint {
+            anchor: Point2D { x: 1.0, y: 0.5 },
+            control_1: Point2D { x: 0.8, y: 0.5 },
+            control_2: Point2D { x: 1.0, y: 0.5 },
+            interpolation: Interpolation::Bezier,
+        },
+    ]
+}
+
+/// Multiply the x coordinates of all points of a curve by the given factor
+pub fn scale_curve(curve: &mut BezierCurve, factor: f64) {
+    curve.iter_mut().for_each(|point| {
+        point.anchor.x *= factor;
+        point.control_1.x *= factor;
+        point.control_2.x *= factor;
+    });
 }
 
 impl Track {
     pub fn new(name: &str, mode: TrackMode) -> Self {
+        let track = Self {
+            name: String::from(name),
+            mode,
+            curve: None,
+            events: None,
+            colors: None,
+            timeline: None,
+            steps: None,
+            components: None,
+            output: None,
+            states: None,
+            generator: None,
+            expression: None,
+            parsed_expression: None,
+        };
         match mode {
             TrackMode::Curve => Self {
-                name: String::from(name),
-                mode: TrackMode::Curve,
-                curve: Some(vec![
-                    AnchorPoint {
-                        anchor: Point2D { x: 0.0, y: 0.5 },
-                        control_1: Point2D { x: 0.0, y: 0.5 },
-                        control_2: Point2D { x: 0.2, y: 0.5 },
-                    },
-                    AnchorPoint {
-                        anchor: Point2D { x: 1.0, y: 0.5 },
-                        control_1: Point2D { x: 0.8, y: 0.5 },
-                        control_2: Point2D { x: 1.0, y: 0.5 },
-                    },
-                ]),
-                events: None,
-                colors: None,
+                curve: Some(default_curve()),
+                ..track
             },
             TrackMode::Event => Self {
-                name: String::from(name),
-                mode: TrackMode::Event,
-                curve: None,
                 events: Some(Vec::new()),
-                colors: None,
+                ..track
             },
             TrackMode::Color => Self {
-                name: String::from(name),
-                mode: TrackMode::Color,
-                curve: None,
-                events: None,
                 colors: Some(vec![
                     ColorStop {
                         position: 0.0,
@@ -147,6 +496,31 @@ impl Track {
                         },
                     },
                 ]),
+                ..track
+            },
+            TrackMode::Timeline => track,
+            TrackMode::Step => Self {
+                steps: Some(vec![StepKeyframe {
+                    position: 0.0,
+                    value: 0.5,
+                }]),
+                ..track
+            },
+            TrackMode::State => Self {
+                states: Some(Vec::new()),
+                ..track
+            },
+            TrackMode::Vector => Self {
+                components: Some(vec![default_curve(), default_curve()]),
+                ..track
+            },
+            TrackMode::Generator => Self {
+                generator: Some(Generator::default()),
+                ..track
+            },
+            TrackMode::Expression => Self {
+                expression: Some(String::new()),
+                ..track
             },
         }
     }
@@ -164,27 +538,168 @@ impl Track {
     //     }
     // }
 
-    pub fn snapshot(&self, prev_position: f64, cur_position: f64) -> TrackSnapshot {
+    /// Multiply all normalized positions of curve anchors, events and color stops by the given
+    /// factor
+    pub fn scale_positions(&mut self, factor: f64) {
+        if let Some(curve) = &mut self.curve {
+            scale_curve(curve, factor);
+        }
+        if let Some(components) = &mut self.components {
+            components
+                .iter_mut()
+                .for_each(|curve| scale_curve(curve, factor));
+        }
+        if let Some(events) = &mut self.events {
+            events.iter_mut().for_each(|event| {
+                event.position *= factor;
+                event.length = event.length.map(|length| length * factor);
+            });
+        }
+        if let Some(colors) = &mut self.colors {
+            colors.iter_mut().for_each(|stop| stop.position *= factor);
+        }
+        if let Some(steps) = &mut self.steps {
+            steps.iter_mut().for_each(|step| step.position *= factor);
+        }
+        if let Some(states) = &mut self.states {
+            states.iter_mut().for_each(|state| state.position *= factor);
+        }
+        if let Some(generator) = &mut self.generator {
+            generator.scale_positions(factor);
+        }
+    }
+
+    /// Get the value of the most recent step keyframe at or before the given position, if any
+    pub fn get_step_value(&self, position: f64) -> Option<f64> {
+        self.steps.as_ref().and_then(|steps| {
+            steps
+                .iter()
+                .filter(|step| step.position <= position)
+                .max_by(|a, b| a.position.total_cmp(&b.position))
+                .map(|step| step.value)
+        })
+    }
+
+    /// Get the most recent state at or before the given position, if any
+    pub fn get_state(&self, position: f64) -> Option<&str> {
+        self.states.as_ref().and_then(|states| {
+            states
+                .iter()
+                .filter(|state| state.position <= position)
+                .max_by(|a, b| a.position.total_cmp(&b.position))
+                .map(|state| state.state.as_str())
+        })
+    }
+
+    /// Check whether an expression can refer to the value of this track, or the given component
+    /// of it
+    pub fn check_reference(
+        &self,
+        component: Option<&str>,
+    ) -> std::result::Result<(), ExpressionError> {
+        let component_count = self
+            .components
+            .as_ref()
+            .map_or(0, |components| components.len());
+        match (self.mode, component) {
+            (
+                TrackMode::Curve | TrackMode::Step | TrackMode::Generator | TrackMode::Expression,
+                None,
+            ) => Ok(()),
+            (TrackMode::Color, Some("r" | "g" | "b" | "a")) => Ok(()),
+            (TrackMode::Vector, Some(component)) if matches!(get_component_index(component), Some(index) if index < component_count) => {
+                Ok(())
+            }
+            (_, None) => Err(ExpressionError::NoValue(self.name.clone())),
+            (_, Some(component)) => Err(ExpressionError::UnknownComponent(
+                self.name.clone(),
+                String::from(component),
+            )),
+        }
+    }
+
+    /// Get the most recent event at or before the given position, or at or after it when
+    /// playing backwards. Events with a length only count while the position lies within them,
+    /// as they have otherwise already ended.
+    pub fn get_event_before(&self, position: f64, forward: bool) -> Option<&EventTrigger> {
+        self.events.as_ref().and_then(|events| {
+            let events = events
+                .iter()
+                .filter(|event| event.length.is_none() || event.is_active_at(position));
+            if forward {
+                events
+                    .filter(|event| event.position <= position)
+                    .max_by(|a, b| a.position.total_cmp(&b.position))
+            } else {
+                // the playhead enters events with a length at their end when playing backwards
+                let entered_at = |event: &EventTrigger| event.get_end().unwrap_or(event.position);
+                events
+                    .filter(|event| entered_at(event) >= position)
+                    .min_by(|a, b| entered_at(a).total_cmp(&entered_at(b)))
+            }
+        })
+    }
+
+    /// Get the state of this track at the current position, including any events that were
+    /// passed by the playhead in the given spans since the previous snapshot
+    pub fn snapshot(&self, spans: &[Span], cur_position: f64, duration: f64) -> TrackSnapshot {
+        let value = if let Some(ref curve) = self.curve {
+            curve.get_value_at_position(cur_position)
+        } else if let Some(ref generator) = self.generator {
+            Some(generator.get_value_at_position(cur_position, duration))
+        } else {
+            self.get_step_value(cur_position)
+        };
+        let values: Option<Vec<Option<f64>>> = self.components.as_ref().map(|components| {
+            components
+                .iter()
+                .map(|curve| curve.get_value_at_position(cur_position))
+                .collect()
+        });
         TrackSnapshot {
             name: self.name.clone(),
-            value: if let Some(ref curve) = self.curve {
-                curve.get_value_at_position(cur_position)
-            } else {
-                None
-            },
+            mapped_value: self
+                .output
+                .as_ref()
+                .and_then(|output| value.map(|v| output.map(v))),
+            mapped_values: self.output.as_ref().and_then(|output| {
+                values
+                    .as_ref()
+                    .map(|values| values.iter().map(|v| v.map(|v| output.map(v))).collect())
+            }),
+            output: self.output.clone(),
+            state: self.get_state(cur_position).map(String::from),
+            value,
+            values,
             events: self.events.as_ref().map(|events| {
                 events.iter().fold(Vec::new(), |mut list, event| {
-                    if event.occurred_between(prev_position, cur_position) {
+                    if event.started_between(spans) {
                         list.push(event.clone());
                     }
                     list
                 })
             }),
+            ended_events: self.events.as_ref().map(|events| {
+                events
+                    .iter()
+                    .filter(|event| event.ended_between(spans))
+                    .cloned()
+                    .collect()
+            }),
+            active_events: self.events.as_ref().map(|events| {
+                events
+                    .iter()
+                    .filter(|event| event.is_active_at(cur_position))
+                    .cloned()
+                    .collect()
+            }),
             color: if let Some(ref colors) = self.colors {
                 colors.get_color_at_position(cur_position)
             } else {
                 None
             },
+            // nested timelines are evaluated by the model, which has access to all timelines
+            timeline: None,
         }
     }
 }
@@ -198,11 +713,63 @@ pub struct Timeline {
     pub duration: f64,
     /// frame rate for playback
     pub fps: u32,
-    /// whether or not to loop playback
-    pub loop_playback: bool,
+    /// what to do when playback reaches the end
+    #[serde(default)]
+    pub loop_mode: LoopMode,
+    /// legacy flag indicating whether or not to loop playback, derived from the loop mode for
+    /// clients that don't know about loop modes. Changing it switches the loop mode.
+    #[serde(default)]
+    loop_playback: Option<bool>,
+    /// normalized start of the region to loop within, if any
+    #[serde(default)]
+    pub in_point: Option<f64>,
+    /// normalized end of the region to loop within, if any
+    #[serde(default)]
+    pub out_point: Option<f64>,
+    /// named positions on the timeline
+    #[serde(default)]
+    pub markers: Vec<Marker>,
+    /// what to do with events skipped over when seeking during playback
+    #[serde(default)]
+    pub seek_events: SeekEvents,
+    /// whether to emit the start of events with a length when seeking into the middle of them
+    /// during playback
+    #[serde(default)]
+    pub start_events_on_seek: bool,
+    /// how keyframes are affected by changes to the duration
+    #[serde(default)]
+    pub keyframe_timing: KeyframeTiming,
+    /// musical tempo and time signature, if any
+    #[serde(default)]
+    pub tempo: Option<Tempo>,
     /// current playback position
     #[serde(skip)]
     position: f64,
+    /// playback speed, where 1.0 is realtime and negative values play backwards
+    #[serde(skip, default = "default_playback_rate")]
+    playback_rate: f64,
+    /// number of times playback has reached the end since it was started
+    #[serde(skip)]
+    loop_iteration: u32,
+    /// whether ping-pong playback is currently on its way back
+    #[serde(skip)]
+    ping_pong_reversed: bool,
+    /// whether the loop region has been released, letting playback continue past it
+    #[serde(skip)]
+    region_released: bool,
+    /// position of the marker to pause at once playback reaches it, if any
+    #[serde(skip)]
+    pause_at: Option<f64>,
+    /// position at which the last span passed by the playhead ended, which the next span leaves
+    /// out if it starts there
+    #[serde(skip)]
+    passed_to: Option<f64>,
+    /// whether playback ran to completion and hasn't been restarted since
+    #[serde(skip)]
+    finished: bool,
+    /// position from which the playhead was moved by seeking during playback, since the last update
+    #[serde(skip)]
+    seeked_from: Option<f64>,
     /// list of tracks contained within this timeline
     tracks: Vec<Track>,
     /// current play state
@@ -211,9 +778,12 @@ pub struct Timeline {
     /// duration of a single frame, based on the fps value
     #[serde(skip)]
     frame_duration: Duration,
-    /// time of last update
-    #[serde(skip, default = "SystemTime::now")]
-    last_updated: SystemTime,
+    /// clock used to advance playback
+    #[serde(skip, default = "default_clock")]
+    clock: Arc<dyn Clock>,
+    /// clock time of last update
+    #[serde(skip)]
+    last_updated: Duration,
     /// indication of whether anything changed and an update should be performed
     #[serde(skip)]
     update_required: bool,
@@ -230,42 +800,99 @@ pub struct TimelineSnapshot {
     pub time: f64,
     /// normalized playhead position at snapshot
     pub position: f64,
+    /// number of the frame at snapshot, counting from 0 at the start of the timeline
+    pub frame: u64,
+    /// SMPTE timecode (HH:MM:SS:FF) at snapshot
+    pub timecode: String,
+    /// bar, beat and tick at snapshot, if the timeline has a tempo
+    pub musical_time: Option<MusicalTime>,
+    /// beats passed by the playhead since the previous snapshot, if the timeline has a tempo
+    pub beats: Vec<Beat>,
+    /// playback speed, where 1.0 is realtime and negative values play backwards
+    pub playback_rate: f64,
+    /// number of times playback has reached the end since it was started
+    pub loop_iteration: u32,
     /// whether or not the timeline is currently playing
     pub is_playing: bool,
+    /// whether or not playback is currently paused
+    pub is_paused: bool,
     /// track snapshots at snapshot time
     pub tracks: Vec<TrackSnapshot>,
+    /// whether playback moved the playhead to get to this snapshot, even if it paused or stopped
+    /// on the way, in which case the events and beats it passed should be emitted
+    #[serde(skip)]
+    pub played: bool,
+    /// spans passed by the playhead since the previous snapshot
+    #[serde(skip)]
+    pub spans: Vec<Span>,
+}
+
+fn default_playback_rate() -> f64 {
+    1.0
 }
 
 impl Timeline {
-    pub fn new(name: &str, duration: f64, fps: u32, loop_playback: bool) -> Self {
+    pub fn new(name: &str, duration: f64, fps: u32, loop_mode: LoopMode) -> Self {
+        let clock = default_clock();
         Self {
             name: String::from(name),
             duration,
             fps,
-            loop_playback,
+            loop_mode,
+            loop_playback: Some(loop_mode.is_looping()),
+            in_point: None,
+            out_point: None,
+            markers: Vec::new(),
+            seek_events: SeekEvents::default(),
+            start_events_on_seek: false,
+            keyframe_timing: KeyframeTiming::default(),
+            tempo: None,
             position: 0.0,
+            playback_rate: default_playback_rate(),
+            loop_iteration: 0,
+            ping_pong_reversed: false,
+            region_released: false,
+            pause_at: None,
+            passed_to: None,
+            finished: false,
+            seeked_from: None,
             tracks: Vec::new(),
             state: PlayState::Stopped,
-            last_updated: SystemTime::now(),
+            last_updated: clock.now(),
+            clock,
             frame_duration: Duration::from_secs_f64(1.0 / f64::from(fps)),
             update_required: true,
         }
     }
 
     pub fn from(src: &Timeline) -> Self {
-        let mut s = Timeline::new(src.name.as_str(), src.duration, src.fps, src.loop_playback);
-        src.get_tracks().iter_mut().for_each(|t| {
-            // if let Some(track) = s.get_track_mut(&t.name) {
+        let mut loop_mode = src.loop_mode;
+        // a legacy loop flag that contradicts the loop mode means the flag was changed by a client
+        // that doesn't know about loop modes
+        if let Some(loop_playback) = src.loop_playback {
+            if loop_playback != loop_mode.is_looping() {
+                loop_mode = if loop_playback {
+                    LoopMode::Loop
+                } else {
+                    LoopMode::Once
+                };
+            }
+        }
+        let mut s = Timeline::new(src.name.as_str(), src.duration, src.fps, loop_mode);
+        s.set_clock(src.clock.clone());
+        src.get_tracks().iter().for_each(|t| {
             if let Ok(track) = s.add_track(t.name.as_str(), t.mode) {
-                track.curve = t.curve.clone();
-                track.events = t.events.clone();
-                track.colors = t.colors.clone();
+                *track = (*t).clone();
             }
         });
-        s.seek(src.get_position());
-        if src.is_playing() {
-            s.play();
-        }
+        s.in_point = src.in_point;
+        s.out_point = src.out_point;
+        s.markers = src.markers.clone();
+        s.seek_events = src.seek_events;
+        s.start_events_on_seek = src.start_events_on_seek;
+        s.keyframe_timing = src.keyframe_timing;
+        s.tempo = src.tempo;
+        s.copy_playback_state(src);
         s.update();
         s
     }
@@ -275,8 +902,127 @@ impl Timeline {
         self.update_required = true;
     }
 
-    pub fn get_position(&self) -> f64 {
-        self.position
+    /// Use a different clock to advance playback, e.g. to step through frames manually
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.last_updated = clock.now();
+        if self.is_playing() {
+            self.state = PlayState::Playing(self.last_updated);
+        }
+        self.clock = clock;
+    }
+
+    /// Take over the position and play state of another timeline, e.g. an older version of this
+    /// one. Both timelines are expected to use the same clock.
+    pub fn copy_playback_state(&mut self, src: &Timeline) {
+        self.position = src.position.clamp(0.0, 1.0);
+        self.playback_rate = src.playback_rate;
+        self.loop_iteration = src.loop_iteration;
+        self.ping_pong_reversed = src.ping_pong_reversed && self.loop_mode == LoopMode::PingPong;
+        self.region_released = src.region_released;
+        self.pause_at = src.pause_at;
+        self.passed_to = src.passed_to;
+        self.finished = src.finished;
+        self.state = src.state;
+        self.update_required = true;
+    }
+
+    /// Keep all keyframes, markers, the loop region and the playhead at the same time in seconds
+    /// after the duration changed from the given one. Keyframes, markers and the loop region past
+    /// the new end are kept, so that they come back when the duration is extended again. Only
+    /// the playhead is limited to the new end.
+    pub fn retime(&mut self, previous_duration: f64) {
+        let factor = previous_duration / self.duration;
+        if !factor.is_finite() || factor <= 0.0 || factor == 1.0 {
+            return;
+        }
+        self.tracks
+            .iter_mut()
+            .for_each(|track| track.scale_positions(factor));
+        self.markers
+            .iter_mut()
+            .for_each(|marker| marker.position *= factor);
+        self.in_point = self.in_point.map(|p| p * factor);
+        self.out_point = self.out_point.map(|p| p * factor);
+        self.pause_at = self.pause_at.map(|p| (p * factor).min(1.0));
+        self.passed_to = self.passed_to.map(|p| (p * factor).min(1.0));
+        self.position = (self.position * factor).min(1.0);
+        self.update_required = true;
+    }
+
+    /// Change the playback speed. This takes effect from the current position onwards, so the
+    /// playhead does not jump when changing speed during playback.
+    pub fn set_playback_rate(&mut self, rate: f64) -> Result<()> {
+        if rate.is_finite() {
+            self.playback_rate = rate;
+            self.update_required = true;
+            Ok(())
+        } else {
+            Err(InvalidDataError)
+        }
+    }
+
+    /// Restrict playback to loop within the given normalized positions, until the region is
+    /// cleared or released
+    pub fn set_loop_region(&mut self, in_point: f64, out_point: f64) -> Result<()> {
+        if (0.0..=1.0).contains(&in_point)
+            && (0.0..=1.0).contains(&out_point)
+            && in_point + POSITION_EPSILON < out_point
+        {
+            self.in_point = Some(in_point);
+            self.out_point = Some(out_point);
+            self.region_released = false;
+            self.update_required = true;
+            Ok(())
+        } else {
+            Err(InvalidDataError)
+        }
+    }
+
+    pub fn clear_loop_region(&mut self) {
+        self.in_point = None;
+        self.out_point = None;
+        self.region_released = false;
+        self.update_required = true;
+    }
+
+    /// Let playback continue past the loop region, until playback finishes
+    pub fn release_loop_region(&mut self) {
+        self.region_released = true;
+        self.update_required = true;
+    }
+
+    /// Get the in and out points of the loop region, if any and if it hasn't been released
+    pub fn get_loop_region(&self) -> Option<(f64, f64)> {
+        if self.region_released || (self.in_point.is_none() && self.out_point.is_none()) {
+            return None;
+        }
+        let in_point = self.in_point.unwrap_or(0.0).clamp(0.0, 1.0);
+        let out_point = self.out_point.unwrap_or(1.0).clamp(0.0, 1.0);
+        // ignore empty or inverted regions, e.g. from invalid incoming data
+        if in_point + POSITION_EPSILON < out_point {
+            Some((in_point, out_point))
+        } else {
+            None
+        }
+    }
+
+    pub fn get_marker(&self, name: &str) -> Option<&Marker> {
+        self.markers.iter().find(|marker| marker.name.eq(name))
+    }
+
+    /// Move the playhead to the position of the named marker
+    pub fn seek_to_marker(&mut self, name: &str) -> Result<()> {
+        let position = self.get_marker(name).ok_or(InvalidDataError)?.position;
+        self.seek(position);
+        Ok(())
+    }
+
+    /// Start or continue playback, and pause once the playhead reaches the named marker
+    pub fn play_to_marker(&mut self, name: &str) -> Result<()> {
+        let position = self.get_marker(name).ok_or(InvalidDataError)?.position;
+        self.play();
+        self.pause_at = Some(position.clamp(0.0, 1.0));
+        Ok(())
     }
 
     pub fn get_tracks(&self) -> Vec<&Track> {
@@ -310,27 +1056,224 @@ impl Timeline {
 
     pub fn play(&mut self) {
         if !self.is_playing() {
-            // Store information on the time and position from which playback was started
-            self.state = PlayState::Playing(SystemTime::now(), self.position);
+            // Store the time from which the playhead should be advanced
+            self.state = PlayState::Playing(self.clock.now());
+            self.finished = false;
             self.update_required = true;
         }
     }
 
+    /// Halt playback, keeping the current position so that playback can be resumed from there
+    pub fn pause(&mut self) {
+        self.state = PlayState::Paused;
+        self.pause_at = None;
+        self.update_required = true;
+    }
+
+    /// Continue playback from the current position, if the timeline was paused
+    pub fn resume(&mut self) {
+        if self.is_paused() {
+            self.state = PlayState::Playing(self.clock.now());
+            self.update_required = true;
+        }
+    }
+
+    /// Halt playback and rewind to the start, or to the in point of the loop region if there is
+    /// one. When playing backwards, the start is the end of the timeline or loop region instead.
     pub fn stop(&mut self) {
         self.state = PlayState::Stopped;
+        self.pause_at = None;
+        self.finished = false;
+        self.loop_iteration = 0;
+        self.ping_pong_reversed = false;
+        self.region_released = false;
+        self.passed_to = None;
+        self.position = if self.playback_rate >= 0.0 {
+            self.in_point.unwrap_or(0.0)
+        } else {
+            self.out_point.unwrap_or(1.0)
+        }
+        .clamp(0.0, 1.0);
         self.update_required = true;
     }
 
     pub fn seek(&mut self, position: f64) {
+        if self.is_playing() {
+            // keep track of where the first seek since the last update started from
+            self.seeked_from.get_or_insert(self.position);
+        }
+        if !(self.is_playing() && self.seek_events == SeekEvents::Fire) {
+            // events at the new position are emitted with the first span from there, unless the
+            // skipped events are fired along with a span up to the new position
+            self.passed_to = None;
+        }
         self.position = position.clamp(0.0, 1.0);
+        self.pause_at = None;
+        self.finished = false;
         if self.is_playing() {
-            self.state = PlayState::Playing(SystemTime::now(), self.position);
+            self.state = PlayState::Playing(self.clock.now());
         }
         self.update_required = true;
     }
 
+    /// Snap the positions of all events to the quantization grid of the tempo, if there is one
+    pub fn quantize_events(&mut self) {
+        if let Some(tempo) = self.tempo {
+            if tempo.quantize.is_none() || self.duration <= 0.0 {
+                return;
+            }
+            let duration = self.duration;
+            self.tracks
+                .iter_mut()
+                .filter_map(|track| track.events.as_mut())
+                .flatten()
+                .for_each(|event| {
+                    let quantize = |position: f64| {
+                        (tempo.quantize(position * duration) / duration).clamp(0.0, 1.0)
+                    };
+                    // snap both ends of events with a length
+                    let end = event.length.map(|length| quantize(event.position + length));
+                    event.position = quantize(event.position);
+                    event.length = end.map(|end| (end - event.position).max(0.0));
+                });
+        }
+    }
+
+    /// Parse the expressions of all tracks that have one, checking that they only refer to
+    /// existing values and don't depend on themselves. Returns the name of each track with an
+    /// invalid expression along with the reason, and leaves those tracks without a value.
+    pub fn compile_expressions(&mut self) -> Vec<(String, ExpressionError)> {
+        let mut errors = Vec::new();
+        let mut parsed: Vec<Option<Expression>> = self
+            .tracks
+            .iter()
+            .map(|track| {
+                let source = track.expression.as_deref().map(str::trim)?;
+                if source.is_empty() {
+                    return None;
+                }
+                let result = Expression::parse(source).and_then(|expression| {
+                    expression.references().into_iter().try_for_each(
+                        |(name, component)| match self.get_track(name) {
+                            Some(referenced) => referenced.check_reference(component),
+                            None => Err(ExpressionError::UnknownTrack(String::from(name))),
+                        },
+                    )?;
+                    Ok(expression)
+                });
+                match result {
+                    Ok(expression) => Some(expression),
+                    Err(err) => {
+                        errors.push((track.name.clone(), err));
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        // reject expressions that depend on their own value through other expressions
+        let cyclic: Vec<usize> = (0..parsed.len())
+            .filter(|index| self.depends_on(&parsed, *index, *index, &mut Vec::new()))
+            .collect();
+        cyclic.iter().for_each(|index| {
+            errors.push((self.tracks[*index].name.clone(), ExpressionError::Cycle));
+            parsed[*index] = None;
+        });
+
+        self.tracks
+            .iter_mut()
+            .zip(parsed)
+            .for_each(|(track, expression)| track.parsed_expression = expression);
+        errors
+    }
+
+    /// Check whether the expression of the track at index `from` refers to the track at index
+    /// `target`, directly or through the expressions of the tracks it refers to
+    fn depends_on(
+        &self,
+        parsed: &[Option<Expression>],
+        from: usize,
+        target: usize,
+        visited: &mut Vec<usize>,
+    ) -> bool {
+        let Some(expression) = &parsed[from] else {
+            return false;
+        };
+        expression.references().into_iter().any(|(name, _)| {
+            match self.tracks.iter().position(|track| track.name.eq(name)) {
+                Some(index) if index == target => true,
+                Some(index) if !visited.contains(&index) => {
+                    visited.push(index);
+                    self.depends_on(parsed, index, target, visited)
+                }
+                _ => false,
+            }
+        })
+    }
+
+    /// Move the playhead to a normalized position, or to the time given by SMPTE timecode
+    /// (HH:MM:SS:FF) or a musical `bar:beat` / `bar:beat:tick` address. Musical addresses are
+    /// only valid if the timeline has a tempo.
+    pub fn seek_to(&mut self, target: &SeekTarget) -> Result<()> {
+        let position = match target {
+            SeekTarget::Position(position) => *position,
+            SeekTarget::Address(address) => {
+                let time = match address.matches(':').count() {
+                    3 => parse_timecode(address, self.fps)?,
+                    1 | 2 => self
+                        .tempo
+                        .ok_or(InvalidDataError)?
+                        .parse_bar_beat(address)?,
+                    _ => return Err(InvalidDataError),
+                };
+                if self.duration > 0.0 {
+                    time / self.duration
+                } else {
+                    0.0
+                }
+            }
+        };
+        if !position.is_finite() {
+            return Err(InvalidDataError);
+        }
+        self.seek(position);
+        Ok(())
+    }
+
+    /// Get the number of the frame at the current position, based on the fps value
+    pub fn get_frame(&self) -> u64 {
+        self.get_frame_at(self.position)
+    }
+
+    fn get_frame_at(&self, position: f64) -> u64 {
+        // allow for rounding errors, so positions on exact frame boundaries don't round down
+        (position * self.duration * f64::from(self.fps) + POSITION_EPSILON).floor() as u64
+    }
+
+    /// Move the playhead by the given number of frames, which is negative to step backwards.
+    /// This is only possible while the timeline isn't playing.
+    pub fn step_frames(&mut self, frames: i64) -> Result<()> {
+        let frames_per_timeline = self.duration * f64::from(self.fps);
+        if self.is_playing() || frames_per_timeline <= 0.0 {
+            Err(InvalidDataError)
+        } else {
+            let frame = (self.get_frame() as i64).saturating_add(frames).max(0);
+            self.seek(frame as f64 / frames_per_timeline);
+            Ok(())
+        }
+    }
+
     pub fn is_playing(&self) -> bool {
-        self.state != PlayState::Stopped
+        matches!(self.state, PlayState::Playing(_))
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state == PlayState::Paused
+    }
+
+    /// Whether playback ran to completion, as opposed to being paused or stopped
+    pub fn has_finished(&self) -> bool {
+        self.finished
     }
 
     /// Update the current position, returning the time code and the current values of any tracks
@@ -338,63 +1281,745 @@ impl Timeline {
     /// Note that this returns Some value only at the specified frame rate for this timeline. Between
     /// "frames", it will return None.
     pub fn update(&mut self) -> Option<TimelineSnapshot> {
-        match self.last_updated.elapsed() {
-            Ok(elapsed) => {
-                if elapsed < self.frame_duration {
-                    None
-                } else {
-                    match &self.state {
-                        PlayState::Stopped => {
-                            self.last_updated = SystemTime::now();
-                            if self.update_required {
-                                self.update_required = false;
-                                Some(self.get_snapshot(self.position))
-                            } else {
-                                None
-                            }
-                        }
-                        PlayState::Playing(started_at, from_position) => {
-                            let prev_position = self.position;
-                            let since_playback_start =
-                                started_at.elapsed().unwrap_or(Duration::ZERO).as_secs_f64();
-                            let new_position = from_position + since_playback_start / self.duration;
-                            if self.loop_playback {
-                                self.position = new_position % 1.0;
-                            } else {
-                                self.position = new_position.clamp(0.0, 1.0);
-                                if self.position == 1.0 {
-                                    self.stop();
-                                }
-                            }
-                            self.last_updated = SystemTime::now();
-                            self.update_required = false;
-                            Some(self.get_snapshot(prev_position))
-                        }
+        let now = self.clock.now();
+        let since_last_update = now.saturating_sub(self.last_updated);
+        if since_last_update < self.frame_duration {
+            None
+        } else {
+            // keep to a steady frame rate by scheduling frames relative to the previous one,
+            // unless updates fell behind by more than a frame
+            self.last_updated = if since_last_update < 2 * self.frame_duration {
+                self.last_updated + self.frame_duration
+            } else {
+                now
+            };
+            match &self.state {
+                PlayState::Paused | PlayState::Stopped => {
+                    self.seeked_from = None;
+                    if self.update_required {
+                        self.update_required = false;
+                        Some(self.get_snapshot(&[Span::at(self.position)], None))
+                    } else {
+                        None
                     }
                 }
+                PlayState::Playing(advanced_at) => {
+                    let elapsed = now.saturating_sub(*advanced_at).as_secs_f64();
+                    self.state = PlayState::Playing(now);
+                    let seeked_from = self.seeked_from.take();
+                    let mut spans = Vec::new();
+                    if let (Some(from), SeekEvents::Fire) = (seeked_from, self.seek_events) {
+                        spans.push(self.pass(from, self.position));
+                    }
+                    spans.append(&mut self.advance(elapsed * self.playback_rate / self.duration));
+                    self.update_required = false;
+                    let mut snapshot = self.get_snapshot(&spans, seeked_from);
+                    snapshot.played = true;
+                    Some(snapshot)
+                }
             }
-            Err(err) => {
-                error!(
-                    "Error getting elapsed time for timeline {}: {}",
-                    self.name, err
-                );
-                None
+        }
+    }
+
+    /// Get the time until this timeline is due to produce its next snapshot, if it has anything
+    /// to update
+    pub fn time_until_next_frame(&self) -> Option<Duration> {
+        if self.is_playing() || self.update_required {
+            Some((self.last_updated + self.frame_duration).saturating_sub(self.clock.now()))
+        } else {
+            None
+        }
+    }
+
+    /// Move the playhead by the given normalized distance, which is negative when playing
+    /// backwards. Returns the spans passed by the playhead, split up wherever playback wrapped
+    /// around or bounced off the start or end of the timeline or its loop region.
+    fn advance(&mut self, distance: f64) -> Vec<Span> {
+        let mut spans = Vec::new();
+        if !distance.is_finite() {
+            // e.g. a timeline without duration; leave the playhead where it is
+            spans.push(self.pass(self.position, self.position));
+            return spans;
+        }
+        let mut from = self.position;
+        let mut remaining = distance.abs();
+        let mut forward = (distance >= 0.0) != self.ping_pong_reversed;
+        let region = self.get_loop_region();
+        loop {
+            // loop within the region if the playhead is headed for its out point, or its in
+            // point when playing backwards
+            let (start, end, in_region) = match region {
+                Some((in_point, out_point)) if forward && from <= out_point => {
+                    (in_point, out_point, true)
+                }
+                Some((in_point, out_point)) if !forward && from >= in_point => {
+                    (out_point, in_point, true)
+                }
+                _ if forward => (0.0, 1.0, false),
+                _ => (1.0, 0.0, false),
+            };
+            if from == start {
+                remaining = self.skip_cycles(start, end, in_region, remaining);
+            }
+            let to_end = (end - from).abs();
+            let reaches_end = remaining + POSITION_EPSILON >= to_end;
+            let to = if reaches_end {
+                end
+            } else if forward {
+                from + remaining
+            } else {
+                from - remaining
+            };
+            // pause on reaching the marker that playback was requested to pause at
+            if let Some(pause_at) = self.pause_at {
+                let span = Span {
+                    from,
+                    to,
+                    includes_from: self.passed_to != Some(from),
+                };
+                if span.contains(pause_at) {
+                    spans.push(self.pass(from, pause_at));
+                    self.position = pause_at;
+                    self.pause_at = None;
+                    self.state = PlayState::Paused;
+                    break;
+                }
+            }
+            spans.push(self.pass(from, to));
+            if !reaches_end {
+                self.position = to;
+                break;
+            }
+            remaining = (remaining - to_end).max(0.0);
+            if in_region {
+                if self.loop_mode == LoopMode::PingPong {
+                    forward = !forward;
+                    self.ping_pong_reversed = !self.ping_pong_reversed;
+                    from = end;
+                } else {
+                    from = start;
+                }
+                continue;
+            }
+            self.loop_iteration = self.loop_iteration.saturating_add(1);
+            match self.loop_mode {
+                LoopMode::Loop => from = start,
+                LoopMode::LoopCount(count) if self.loop_iteration < count => from = start,
+                LoopMode::PingPong => {
+                    forward = !forward;
+                    self.ping_pong_reversed = !self.ping_pong_reversed;
+                    from = end;
+                }
+                LoopMode::OnceRewind => {
+                    self.position = start;
+                    self.finish();
+                    break;
+                }
+                LoopMode::Once | LoopMode::LoopCount(_) => {
+                    self.position = end;
+                    self.finish();
+                    break;
+                }
+            }
+        }
+        spans
+    }
+
+    /// Get the distance that remains after skipping whole cycles from the given start to end,
+    /// keeping count of the loops skipped over. Only the last pass in each direction is left, so
+    /// that a high playback rate or a tiny loop region doesn't produce a span for every cycle.
+    fn skip_cycles(&mut self, start: f64, end: f64, in_region: bool, remaining: f64) -> f64 {
+        let ping_pong = self.loop_mode == LoopMode::PingPong;
+        let repeats = in_region
+            || matches!(
+                self.loop_mode,
+                LoopMode::Loop | LoopMode::LoopCount(_) | LoopMode::PingPong
+            );
+        // playback has to pause within the next cycle anyway
+        let pauses = self
+            .pause_at
+            .is_some_and(|pause_at| (start.min(end)..=start.max(end)).contains(&pause_at));
+        let cycle = (end - start).abs();
+        // skip back and forth when ping-ponging, so that playback keeps going the same way
+        let period = if ping_pong { 2.0 * cycle } else { cycle };
+        if !repeats || pauses || cycle <= 0.0 || remaining < cycle + period {
+            return remaining;
+        }
+        let whole = ((remaining - cycle) / period).floor();
+        let mut periods = whole;
+        if !in_region {
+            if let LoopMode::LoopCount(count) = self.loop_mode {
+                // leave the last loop to finish playback
+                let left = count.saturating_sub(self.loop_iteration.saturating_add(1));
+                periods = periods.min(f64::from(left));
             }
+            let loops = if ping_pong { 2.0 * periods } else { periods };
+            self.loop_iteration = self.loop_iteration.saturating_add(loops as u32);
         }
+        if periods < whole {
+            remaining - periods * period
+        } else {
+            cycle + (remaining - cycle).rem_euclid(period)
+        }
+    }
+
+    /// Get the span passed by the playhead from one position to another, which leaves out the
+    /// start if the previous span ended there
+    fn pass(&mut self, from: f64, to: f64) -> Span {
+        let span = Span {
+            from,
+            to,
+            includes_from: self.passed_to != Some(from),
+        };
+        self.passed_to = Some(to);
+        span
     }
 
-    fn get_snapshot(&self, prev_position: f64) -> TimelineSnapshot {
+    /// Stop playback without rewinding, once the loop mode doesn't allow it to continue
+    fn finish(&mut self) {
+        self.state = PlayState::Stopped;
+        self.pause_at = None;
+        self.finished = true;
+        self.loop_iteration = 0;
+        self.ping_pong_reversed = false;
+        self.region_released = false;
+    }
+
+    /// Get a snapshot of this timeline playing nested within a track of another timeline, based
+    /// on the snapshot of that parent timeline
+    pub fn get_nested_snapshot(
+        &self,
+        nested: &NestedTimeline,
+        parent: &TimelineSnapshot,
+    ) -> TimelineSnapshot {
+        let to_position = |parent_position: f64| {
+            let position = (parent_position * parent.duration - nested.offset) * nested.time_scale
+                / self.duration;
+            if position.is_finite() {
+                position
+            } else {
+                0.0
+            }
+        };
+        // only keep the parts of the parent spans that overlap with the nested timeline; a span
+        // that enters it from the outside includes the start or end it entered at
+        let spans: Vec<Span> = parent
+            .spans
+            .iter()
+            .map(|span| Span {
+                from: to_position(span.from),
+                to: to_position(span.to),
+                includes_from: span.includes_from,
+            })
+            .filter(|span| span.from.max(span.to) >= 0.0 && span.from.min(span.to) <= 1.0)
+            .map(|span| {
+                let from = span.from.clamp(0.0, 1.0);
+                Span {
+                    from,
+                    to: span.to.clamp(0.0, 1.0),
+                    includes_from: span.includes_from || from != span.from,
+                }
+            })
+            .filter(|span| span.from != span.to || span.includes_from)
+            .collect();
+        let mut snapshot =
+            self.get_snapshot_at(to_position(parent.position).clamp(0.0, 1.0), &spans, None);
+        snapshot.playback_rate = parent.playback_rate * nested.time_scale;
+        snapshot.loop_iteration = 0;
+        snapshot.is_playing = parent.is_playing;
+        snapshot.is_paused = parent.is_paused;
+        snapshot.played = parent.played;
+        snapshot
+    }
+
+    /// Get the state of all tracks at the current position, including the events passed in the
+    /// given spans. If the playhead was moved by seeking since the previous snapshot, events are
+    /// added according to the seek settings of the timeline.
+    fn get_snapshot(&self, spans: &[Span], seeked_from: Option<f64>) -> TimelineSnapshot {
+        self.get_snapshot_at(self.position, spans, seeked_from)
+    }
+
+    fn get_snapshot_at(
+        &self,
+        position: f64,
+        spans: &[Span],
+        seeked_from: Option<f64>,
+    ) -> TimelineSnapshot {
+        let chase = seeked_from.is_some() && self.seek_events == SeekEvents::Chase;
+        let mut tracks: Vec<TrackSnapshot> = self
+            .tracks
+            .iter()
+            .map(|track| {
+                let mut snapshot = track.snapshot(spans, position, self.duration);
+                if chase {
+                    let forward = self.playback_rate >= 0.0;
+                    if let (Some(events), Some(event)) = (
+                        snapshot.events.as_mut(),
+                        track.get_event_before(position, forward),
+                    ) {
+                        if !events.iter().any(|e| e.id.eq(&event.id)) {
+                            events.insert(0, event.clone());
+                        }
+                    }
+                }
+                if let (Some(from), true) = (seeked_from, self.start_events_on_seek) {
+                    // start the events that the playhead landed in the middle of
+                    if let (Some(events), Some(active_events)) =
+                        (snapshot.events.as_mut(), snapshot.active_events.as_ref())
+                    {
+                        active_events
+                            .iter()
+                            .filter(|event| !event.is_active_at(from))
+                            .for_each(|event| {
+                                if !events.iter().any(|e| e.id.eq(&event.id)) {
+                                    events.push(event.clone());
+                                }
+                            });
+                    }
+                }
+                snapshot
+            })
+            .collect();
+        self.evaluate_expressions(&mut tracks, position);
         TimelineSnapshot {
             name: self.name.clone(),
             duration: self.duration,
-            time: self.position * self.duration,
-            position: self.position,
+            time: position * self.duration,
+            position,
+            frame: self.get_frame_at(position),
+            timecode: format_timecode(self.get_frame_at(position), self.fps),
+            musical_time: self
+                .tempo
+                .and_then(|tempo| tempo.get_musical_time(position * self.duration)),
+            beats: self.tempo.map_or_else(Vec::new, |tempo| {
+                spans
+                    .iter()
+                    .flat_map(|span| {
+                        tempo.get_beats_between(span.from * self.duration, span.to * self.duration)
+                    })
+                    .collect()
+            }),
+            playback_rate: self.playback_rate,
+            loop_iteration: self.loop_iteration,
             is_playing: self.is_playing(),
-            tracks: self
-                .tracks
+            is_paused: self.is_paused(),
+            tracks,
+            spans: spans.to_vec(),
+            played: false,
+        }
+    }
+
+    /// Set the values of expression tracks from the snapshots of the tracks they refer to,
+    /// evaluating expressions that other expressions refer to first
+    fn evaluate_expressions(&self, snapshots: &mut [TrackSnapshot], position: f64) {
+        let mut pending: Vec<usize> = (0..self.tracks.len())
+            .filter(|index| self.tracks[*index].parsed_expression.is_some())
+            .collect();
+        while !pending.is_empty() {
+            let ready: Vec<usize> = pending
                 .iter()
-                .map(|track| track.snapshot(prev_position, self.position))
-                .collect(),
+                .copied()
+                .filter(|index| {
+                    self.tracks[*index]
+                        .parsed_expression
+                        .iter()
+                        .flat_map(|expression| expression.references())
+                        .all(|(name, _)| !pending.iter().any(|p| self.tracks[*p].name.eq(name)))
+                })
+                .collect();
+            // cycles are rejected when compiling expressions, but make sure not to get stuck
+            if ready.is_empty() {
+                break;
+            }
+            ready.iter().for_each(|index| {
+                let track = &self.tracks[*index];
+                let value = track.parsed_expression.as_ref().and_then(|expression| {
+                    expression.evaluate(
+                        &|name, component| {
+                            snapshots
+                                .iter()
+                                .find(|snapshot| snapshot.name.eq(name))
+                                .and_then(|snapshot| snapshot.get_component(component))
+                        },
+                        position * self.duration,
+                        position,
+                    )
+                });
+                let snapshot = &mut snapshots[*index];
+                snapshot.value = value;
+                snapshot.mapped_value = track
+                    .output
+                    .as_ref()
+                    .and_then(|output| value.map(|v| output.map(v)));
+            });
+            pending.retain(|index| !ready.contains(index));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+
+    /// Timeline of one second at 10 fps, driven by a manual clock
+    fn timeline(loop_mode: LoopMode) -> (Timeline, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::new());
+        let mut timeline = Timeline::new("test", 1.0, 10, loop_mode);
+        timeline.set_clock(clock.clone());
+        (timeline, clock)
+    }
+
+    /// Move the clock on by a frame at a time, returning the snapshots produced along the way
+    fn play_frames(
+        timeline: &mut Timeline,
+        clock: &ManualClock,
+        frames: u32,
+    ) -> Vec<TimelineSnapshot> {
+        (0..frames)
+            .filter_map(|_| {
+                clock.advance(Duration::from_millis(100));
+                timeline.update()
+            })
+            .collect()
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "expected {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn advance_stops_at_end() {
+        let (mut timeline, _) = timeline(LoopMode::Once);
+        timeline.play();
+        let spans = timeline.advance(1.5);
+        assert_eq!(
+            spans,
+            vec![Span {
+                from: 0.0,
+                to: 1.0,
+                includes_from: true
+            }]
+        );
+        assert_eq!(timeline.position, 1.0);
+        assert!(!timeline.is_playing());
+        assert!(timeline.has_finished());
+    }
+
+    #[test]
+    fn advance_wraps_around_when_looping() {
+        let (mut timeline, _) = timeline(LoopMode::Loop);
+        timeline.seek(0.85);
+        timeline.play();
+        let spans = timeline.advance(0.3);
+        assert_eq!(spans.len(), 2);
+        assert_near(spans[0].to, 1.0);
+        assert_near(spans[1].from, 0.0);
+        assert_near(timeline.position, 0.15);
+        assert_eq!(timeline.loop_iteration, 1);
+        assert!(timeline.is_playing());
+
+        // and the other way around when playing backwards
+        let spans = timeline.advance(-0.3);
+        assert_eq!(spans.len(), 2);
+        assert_near(timeline.position, 0.85);
+    }
+
+    #[test]
+    fn advance_bounces_when_ping_ponging() {
+        let (mut timeline, _) = timeline(LoopMode::PingPong);
+        timeline.seek(0.9);
+        timeline.play();
+        let spans = timeline.advance(0.2);
+        assert_eq!(spans.len(), 2);
+        assert_near(timeline.position, 0.9);
+        assert!(timeline.ping_pong_reversed);
+
+        // bounce off the start as well, heading forwards again
+        timeline.advance(1.0);
+        assert_near(timeline.position, 0.1);
+        assert!(!timeline.ping_pong_reversed);
+        assert!(timeline.is_playing());
+    }
+
+    #[test]
+    fn advance_skips_whole_loops() {
+        let (mut timeline, _) = timeline(LoopMode::Loop);
+        timeline.play();
+        let spans = timeline.advance(1e8 + 0.25);
+        assert_eq!(spans.len(), 2);
+        assert_near(timeline.position, 0.25);
+        assert_eq!(timeline.loop_iteration, 100_000_000);
+    }
+
+    #[test]
+    fn advance_skips_loops_up_to_loop_count() {
+        let (mut timeline, _) = timeline(LoopMode::LoopCount(3));
+        timeline.play();
+        let spans = timeline.advance(1e8);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(timeline.position, 1.0);
+        assert!(timeline.has_finished());
+    }
+
+    #[test]
+    fn advance_skips_whole_round_trips_when_ping_ponging() {
+        let (mut timeline, _) = timeline(LoopMode::PingPong);
+        timeline.play();
+        let spans = timeline.advance(1e8 + 1.25);
+        assert!(spans.len() <= 3);
+        assert_near(timeline.position, 0.75);
+        assert!(timeline.ping_pong_reversed);
+    }
+
+    #[test]
+    fn advance_wraps_within_loop_region() {
+        let (mut timeline, _) = timeline(LoopMode::Once);
+        timeline.set_loop_region(0.2, 0.6).unwrap();
+        timeline.play();
+        timeline.advance(0.5);
+        assert_near(timeline.position, 0.5);
+        let spans = timeline.advance(0.4);
+        assert_eq!(spans.len(), 2);
+        assert_near(spans[1].from, 0.2);
+        assert_near(timeline.position, 0.5);
+
+        // once released, playback continues past the region up to the end
+        timeline.release_loop_region();
+        timeline.advance(0.8);
+        assert_eq!(timeline.position, 1.0);
+        assert!(!timeline.is_playing());
+    }
+
+    #[test]
+    fn advance_skips_whole_loops_within_tiny_loop_region() {
+        let (mut timeline, _) = timeline(LoopMode::Once);
+        timeline.set_loop_region(0.5, 0.5 + 1e-8).unwrap();
+        timeline.seek(0.49);
+        timeline.play();
+        let spans = timeline.advance(0.017);
+        assert!(spans.len() <= 4);
+        assert!((0.5..=0.5 + 1e-8).contains(&timeline.position));
+        assert!(timeline.is_playing());
+    }
+
+    /// Get the ids of the events that started in a snapshot of the first track
+    fn started_events(snapshot: &TimelineSnapshot) -> Vec<&str> {
+        snapshot.tracks[0]
+            .events
+            .iter()
+            .flatten()
+            .map(|event| event.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn event_running_past_end_ends_on_last_frame() {
+        let (mut timeline, clock) = timeline(LoopMode::Once);
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(vec![EventTrigger {
+            id: String::from("long"),
+            position: 0.8,
+            length: Some(0.5),
+            data: serde_json::Value::Null,
+        }]);
+        timeline.play();
+        let snapshots = play_frames(&mut timeline, &clock, 12);
+        let last = snapshots.last().unwrap();
+        assert_eq!(last.position, 1.0);
+        assert!(last.played && !last.is_playing);
+        let ended: Vec<&str> = last.tracks[0]
+            .ended_events
+            .iter()
+            .flatten()
+            .map(|event| event.id.as_str())
+            .collect();
+        assert_eq!(ended, vec!["long"]);
+    }
+
+    #[test]
+    fn chase_leaves_out_events_that_ended() {
+        let (mut timeline, clock) = timeline(LoopMode::Once);
+        timeline.seek_events = SeekEvents::Chase;
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(vec![
+            EventTrigger {
+                id: String::from("cue"),
+                position: 0.05,
+                length: None,
+                data: serde_json::Value::Null,
+            },
+            EventTrigger {
+                id: String::from("long"),
+                position: 0.1,
+                length: Some(0.3),
+                data: serde_json::Value::Null,
+            },
+        ]);
+        timeline.play();
+        play_frames(&mut timeline, &clock, 1);
+        timeline.seek(0.7);
+        let snapshots = play_frames(&mut timeline, &clock, 1);
+        assert_eq!(started_events(&snapshots[0]), vec!["cue"]);
+
+        // but chases an event with a length that the playhead landed in
+        timeline.seek(0.15);
+        let snapshots = play_frames(&mut timeline, &clock, 1);
+        assert_eq!(started_events(&snapshots[0]), vec!["long"]);
+    }
+
+    #[test]
+    fn retime_keeps_event_times() {
+        let (mut timeline, _) = timeline(LoopMode::Once);
+        timeline.duration = 10.0;
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(vec![EventTrigger {
+            id: String::from("long"),
+            position: 0.25,
+            length: Some(0.25),
+            data: serde_json::Value::Null,
+        }]);
+        timeline.duration = 20.0;
+        timeline.retime(10.0);
+        let event = &timeline.tracks[0].events.as_ref().unwrap()[0];
+        assert_near(event.position * timeline.duration, 2.5);
+        assert_near(event.length.unwrap() * timeline.duration, 2.5);
+    }
+
+    #[test]
+    fn retime_keeps_cues_past_the_end() {
+        let (mut timeline, _) = timeline(LoopMode::Once);
+        timeline.duration = 10.0;
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(vec![EventTrigger {
+            id: String::from("cue"),
+            position: 0.8,
+            length: None,
+            data: serde_json::Value::Null,
+        }]);
+        timeline.markers.push(Marker {
+            name: String::from("marker"),
+            position: 0.9,
+        });
+        timeline.set_loop_region(0.6, 0.9).unwrap();
+
+        timeline.duration = 5.0;
+        timeline.retime(10.0);
+        assert_eq!(timeline.get_loop_region(), None);
+        timeline.duration = 10.0;
+        timeline.retime(5.0);
+
+        assert_near(timeline.tracks[0].events.as_ref().unwrap()[0].position, 0.8);
+        assert_near(timeline.markers[0].position, 0.9);
+        let (in_point, out_point) = timeline.get_loop_region().unwrap();
+        assert_near(in_point, 0.6);
+        assert_near(out_point, 0.9);
+    }
+
+    #[test]
+    fn events_on_frame_boundaries_fire_once() {
+        let (mut timeline, clock) = timeline(LoopMode::Loop);
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(
+            [0.0, 0.2, 0.5, 1.0]
+                .iter()
+                .map(|position| EventTrigger {
+                    id: position.to_string(),
+                    position: *position,
+                    length: None,
+                    data: serde_json::Value::Null,
+                })
+                .collect(),
+        );
+        timeline.play();
+        let snapshots = play_frames(&mut timeline, &clock, 20);
+        let mut fired: Vec<&str> = snapshots.iter().flat_map(started_events).collect();
+        fired.sort();
+        // the start is passed again as playback wraps around at the end of the second loop
+        assert_eq!(
+            fired,
+            vec!["0", "0", "0", "0.2", "0.2", "0.5", "0.5", "1", "1"]
+        );
+    }
+
+    #[test]
+    fn playback_pauses_at_marker() {
+        let (mut timeline, clock) = timeline(LoopMode::Loop);
+        timeline.markers.push(Marker {
+            name: String::from("cue"),
+            position: 0.45,
+        });
+        let track = timeline.add_track("events", TrackMode::Event).unwrap();
+        track.events = Some(vec![EventTrigger {
+            id: String::from("cue"),
+            position: 0.45,
+            length: None,
+            data: serde_json::Value::Null,
+        }]);
+        timeline.play_to_marker("cue").unwrap();
+        let snapshots = play_frames(&mut timeline, &clock, 5);
+        assert!(timeline.is_paused());
+        assert_near(timeline.position, 0.45);
+
+        // the event at the marker is emitted along with reaching it
+        let last = snapshots.last().unwrap();
+        assert_near(last.position, 0.45);
+        assert!(last.played && !last.is_playing);
+        assert_eq!(started_events(last), vec!["cue"]);
+
+        // stay paused at the marker until playback is resumed
+        assert!(play_frames(&mut timeline, &clock, 5).is_empty());
+        assert_near(timeline.position, 0.45);
+
+        // without emitting the event at the marker again
+        timeline.resume();
+        let snapshots = play_frames(&mut timeline, &clock, 1);
+        assert_near(snapshots[0].position, 0.55);
+        assert!(started_events(&snapshots[0]).is_empty());
+    }
+
+    #[test]
+    fn compile_expressions_rejects_cycles() {
+        let (mut timeline, _clock) = timeline(LoopMode::Once);
+        [
+            ("a", "b + 1"),
+            ("b", "a * 2"),
+            ("c", "a"),
+            ("d", "d"),
+            ("e", "c + missing"),
+            ("f", "c * 2"),
+        ]
+        .into_iter()
+        .for_each(|(name, expression)| {
+            timeline
+                .add_track(name, TrackMode::Expression)
+                .unwrap()
+                .expression = Some(String::from(expression));
+        });
+        let errors = timeline.compile_expressions();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    String::from("e"),
+                    ExpressionError::UnknownTrack(String::from("missing"))
+                ),
+                (String::from("a"), ExpressionError::Cycle),
+                (String::from("b"), ExpressionError::Cycle),
+                (String::from("d"), ExpressionError::Cycle),
+            ]
+        );
+        let compiled: Vec<&str> = timeline
+            .tracks
+            .iter()
+            .filter(|track| track.parsed_expression.is_some())
+            .map(|track| track.name.as_str())
+            .collect();
+        assert_eq!(compiled, vec!["c", "f"]);
+    }
+}